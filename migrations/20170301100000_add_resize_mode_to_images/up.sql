@@ -0,0 +1 @@
+ALTER TABLE images ADD COLUMN resize_mode INT NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+DROP INDEX images_derivative_size;
@@ -0,0 +1,21 @@
+-- Duplicates give way to the oldest row of their size, everything that still points at them moves over to it.
+-- Their files are left behind on purpose, `cleanup_orphans` removes them once no row refers to them anymore.
+CREATE TEMPORARY TABLE duplicate_images AS
+    SELECT a.id AS old_id, MIN(b.id) AS new_id
+    FROM images a JOIN images b
+        ON a.parent_id = b.parent_id
+       AND a.wanted_width = b.wanted_width
+       AND a.wanted_height = b.wanted_height
+       AND a.resize_mode = b.resize_mode
+       AND a.id > b.id
+    GROUP BY a.id;
+
+UPDATE images SET parent_id = d.new_id FROM duplicate_images d WHERE images.parent_id = d.old_id;
+UPDATE users SET profile_image = d.new_id FROM duplicate_images d WHERE users.profile_image = d.old_id;
+UPDATE user_profiles SET banner_image = d.new_id FROM duplicate_images d WHERE user_profiles.banner_image = d.old_id;
+UPDATE submissions SET image = d.new_id FROM duplicate_images d WHERE submissions.image = d.old_id;
+
+DELETE FROM images USING duplicate_images d WHERE images.id = d.old_id;
+DROP TABLE duplicate_images;
+
+CREATE UNIQUE INDEX images_derivative_size ON images (parent_id, wanted_width, wanted_height, resize_mode);
@@ -0,0 +1,2 @@
+ALTER TABLE images ADD COLUMN content_hash VARCHAR;
+CREATE INDEX images_content_hash ON images (content_hash);
@@ -0,0 +1 @@
+ALTER TABLE images DROP COLUMN byte_size;
@@ -0,0 +1,3 @@
+ALTER TABLE images ADD COLUMN byte_size BIGINT NOT NULL DEFAULT 0;
+-- Inline images can be backfilled right away, local files need to be stat'ed
+UPDATE images SET byte_size = length(decode(path, 'base64')) WHERE host_type = 1;
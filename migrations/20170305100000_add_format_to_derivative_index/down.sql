@@ -0,0 +1,8 @@
+DROP INDEX images_derivative_size;
+DELETE FROM images a USING images b
+    WHERE a.parent_id = b.parent_id
+      AND a.wanted_width = b.wanted_width
+      AND a.wanted_height = b.wanted_height
+      AND a.resize_mode = b.resize_mode
+      AND a.id > b.id;
+CREATE UNIQUE INDEX images_derivative_size ON images (parent_id, wanted_width, wanted_height, resize_mode);
@@ -0,0 +1,2 @@
+DROP INDEX images_derivative_size;
+CREATE UNIQUE INDEX images_derivative_size ON images (parent_id, wanted_width, wanted_height, resize_mode, format);
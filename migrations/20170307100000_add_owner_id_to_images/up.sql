@@ -0,0 +1,2 @@
+ALTER TABLE images ADD COLUMN owner_id BIGINT references users(id);
+CREATE INDEX images_owner_id ON images (owner_id);
@@ -0,0 +1 @@
+ALTER TABLE images DROP COLUMN accent_color;
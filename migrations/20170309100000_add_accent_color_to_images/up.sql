@@ -0,0 +1 @@
+ALTER TABLE images ADD COLUMN accent_color INT;
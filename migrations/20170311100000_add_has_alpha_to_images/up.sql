@@ -0,0 +1,2 @@
+-- Images stored before this report no transparency until they are regenerated
+ALTER TABLE images ADD COLUMN has_alpha BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,3 @@
+ALTER TABLE images ADD COLUMN transform INT NOT NULL DEFAULT 0;
+DROP INDEX images_derivative_size;
+CREATE UNIQUE INDEX images_derivative_size ON images (parent_id, wanted_width, wanted_height, resize_mode, format, transform);
@@ -0,0 +1,2 @@
+DROP INDEX images_last_accessed_at;
+ALTER TABLE images DROP COLUMN last_accessed_at;
@@ -0,0 +1,2 @@
+ALTER TABLE images ADD COLUMN last_accessed_at TIMESTAMP NOT NULL DEFAULT now();
+CREATE INDEX images_last_accessed_at ON images (last_accessed_at) WHERE parent_id IS NOT NULL;
@@ -0,0 +1,4 @@
+DELETE FROM images WHERE resize_mode = 4;
+DROP INDEX images_derivative_size;
+CREATE UNIQUE INDEX images_derivative_size ON images (parent_id, wanted_width, wanted_height, resize_mode, format, transform);
+ALTER TABLE images DROP COLUMN background;
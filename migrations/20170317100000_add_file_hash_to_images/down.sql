@@ -0,0 +1,2 @@
+DROP INDEX images_file_hash;
+ALTER TABLE images DROP COLUMN file_hash;
@@ -0,0 +1,2 @@
+ALTER TABLE images ADD COLUMN file_hash VARCHAR;
+CREATE INDEX images_file_hash ON images (file_hash);
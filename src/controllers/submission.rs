@@ -59,7 +59,7 @@ pub fn show(req: &mut Request) -> IronResult<Response> {
         image: {
             let image = try!(submission.get_image());
             if let Some(image) = image {
-                Some(url!(image.get_with_size(1000, 1000)?.get_path()))
+                Some(url!(image.get_with_size(1000, 1000)?.get_path()?))
             } else {
                 None
             }
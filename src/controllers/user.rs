@@ -152,7 +152,7 @@ pub fn update(req: &mut Request) -> IronResult<Response> {
         _ => None
     };
 
-    let update_user = match models::user::UpdateUser::new(username, password, avatar) {
+    let update_user = match models::user::UpdateUser::new(user.id, username, password, avatar) {
         Ok(update_user) => update_user,
         Err(err) => {
             let mut resp = Response::with((status::Ok, try!(views::user::edit(&user, Some(err), &data))));
@@ -1,19 +1,157 @@
 use std::env;
+use std::thread;
+use std::time::Duration;
 
 use diesel::pg::PgConnection;
 use r2d2_diesel::ConnectionManager;
 use r2d2;
 
+use error;
+
+pub type Connection = r2d2::PooledConnection<ConnectionManager<PgConnection>>;
+
 lazy_static! {
     static ref CONNECTION: r2d2::Pool<ConnectionManager<PgConnection>> = {
         let database_url = env::var("DATABASE_URL")
             .expect("DATABASE_URL must be set");
-        let config = r2d2::Config::default();
+        let config = r2d2::Config::builder()
+            .connection_timeout(Duration::from_millis(*POOL_TIMEOUT_MS))
+            .build();
         let manager = ConnectionManager::<PgConnection>::new(database_url);
         r2d2::Pool::new(config, manager).expect("Failed to create pool")
     };
+
+    /// How often a timed out checkout gets retried before giving up
+    static ref POOL_RETRIES: u32 = {
+        env::var("DATABASE_POOL_RETRIES")
+            .map(|s| s.parse().expect("DATABASE_POOL_RETRIES must be a number"))
+            .unwrap_or(2)
+    };
+
+    /// How long a single checkout waits for a free connection, the retries come on top of that
+    static ref POOL_TIMEOUT_MS: u64 = {
+        env::var("DATABASE_POOL_TIMEOUT_MS")
+            .map(|s| s.parse().expect("DATABASE_POOL_TIMEOUT_MS must be a number"))
+            .unwrap_or(1000)
+    };
 }
 
+static POOL_BACKOFF_MS : u64 = 50;
+
 pub fn connection() -> r2d2::Pool<ConnectionManager<PgConnection>> {
     CONNECTION.clone()
 }
+
+pub fn conn() -> Result<Connection, error::FurryError> {
+    conn_with_retries(*POOL_RETRIES)
+}
+
+/// Checks out a connection, retrying with a doubling pause in between if the pool is exhausted
+pub fn conn_with_retries(retries: u32) -> Result<Connection, error::FurryError> {
+    checkout(&CONNECTION, retries)
+}
+
+fn checkout<M: r2d2::ManageConnection>(pool: &r2d2::Pool<M>, retries: u32)
+    -> Result<r2d2::PooledConnection<M>, error::FurryError>
+{
+    let mut backoff = POOL_BACKOFF_MS;
+    let mut attempt = 0;
+    loop {
+        match pool.get() {
+            Ok(c) => return Ok(c),
+            Err(e) => {
+                if attempt >= retries {
+                    return Err(e.into());
+                }
+                warn!("Connection pool exhausted, retrying in {}ms", backoff);
+                thread::sleep(Duration::from_millis(backoff));
+                backoff *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::fmt;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use r2d2;
+
+    use error;
+    use super::checkout;
+
+    #[derive(Debug)]
+    struct NeverFails;
+
+    impl fmt::Display for NeverFails {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("never fails")
+        }
+    }
+
+    impl Error for NeverFails {
+        fn description(&self) -> &str {
+            "never fails"
+        }
+    }
+
+    /// Hands out connections that are nothing but a placeholder
+    struct MockManager;
+
+    impl r2d2::ManageConnection for MockManager {
+        type Connection = ();
+        type Error = NeverFails;
+
+        fn connect(&self) -> Result<(), NeverFails> {
+            Ok(())
+        }
+
+        fn is_valid(&self, _conn: &mut ()) -> Result<(), NeverFails> {
+            Ok(())
+        }
+
+        fn has_broken(&self, _conn: &mut ()) -> bool {
+            false
+        }
+    }
+
+    fn single_connection_pool() -> r2d2::Pool<MockManager> {
+        let config = r2d2::Config::builder()
+            .pool_size(1)
+            .connection_timeout(Duration::from_millis(10))
+            .build();
+        r2d2::Pool::new(config, MockManager).unwrap()
+    }
+
+    #[test]
+    fn exhausted_pool_times_out_after_retrying() {
+        let pool = single_connection_pool();
+        let _held = checkout(&pool, 0).unwrap();
+
+        let start = Instant::now();
+        match checkout(&pool, 2) {
+            Err(error::FurryError::PoolTimeout(_)) => {}
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("got a second connection out of a pool of one"),
+        }
+        // Three short timeouts and 50ms + 100ms of backing off, nowhere near r2d2's default of 30s each
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn retry_succeeds_once_a_connection_is_returned() {
+        let pool = single_connection_pool();
+        let held = checkout(&pool, 0).unwrap();
+        let release = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(30));
+            drop(held);
+        });
+
+        assert!(checkout(&pool, 3).is_ok());
+        release.join().unwrap();
+    }
+}
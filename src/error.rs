@@ -7,6 +7,7 @@ use bcrypt::BcryptError;
 use diesel;
 use std::error::Error;
 use models::user::User;
+use models::image::{ImageFormat, UploadFormat};
 use std::fmt;
 
 quick_error! {
@@ -25,6 +26,11 @@ quick_error! {
             description(err.description())
             from(e: diesel::result::Error) -> (Box::new(e))
         }
+        PoolTimeout(err: ::r2d2::GetTimeout) {
+            cause(err)
+            description(err.description())
+            from()
+        }
         Login(err: Box<Error + Send>) {
             cause(&**err)
             description(err.description())
@@ -38,11 +44,134 @@ quick_error! {
             description(err.description())
         }
         Image(err: ::image::ImageError) {
+            cause(err)
+            description(err.description())
+        }
+        Decode(err: ::image::ImageError) {
+            cause(err)
+            description(err.description())
+        }
+        UnsupportedImage(err: ::image::ImageError) {
+            cause(err)
+            description(err.description())
+        }
+        Gif(err: ::gif::DecodingError) {
+            cause(err)
+            description(err.description())
             from()
         }
         Io(err: ::std::io::Error) {
             from()
         }
+        FileIo { op: &'static str, path: String, err: ::std::io::Error } {
+            cause(err)
+            description(err.description())
+            display("Could not {} {}: {}", op, path, err)
+        }
+        Fetch(err: ::hyper::Error) {
+            cause(err)
+            description(err.description())
+            from()
+        }
+        FetchStatus(status: ::hyper::status::StatusCode) {
+            description("remote server did not return the image")
+            display("Remote server answered with {}", status)
+        }
+        FetchForbidden(url: String) {
+            description("remote address is not allowed")
+            display("Refusing to fetch {}, it does not point to a public address", url)
+        }
+        FetchTooLarge(max: u64) {
+            description("remote image is too large")
+            display("Remote image is larger than {} bytes", max)
+        }
+        UploadTooLarge(max: u64) {
+            description("uploaded image is too large")
+            display("Uploaded image is larger than {} bytes", max)
+        }
+        S3Status { op: &'static str, key: String, status: ::hyper::status::StatusCode } {
+            description("object storage request failed")
+            display("Could not {} {} from object storage, it answered with {}", op, key, status)
+        }
+        S3NotConfigured {
+            description("object storage is not configured")
+        }
+        DerivativeLimit { id: i64, max: i64 } {
+            description("image has too many derivatives")
+            display("Image {} already has the maximum of {} derivatives", id, max)
+        }
+        NotADerivative(id: i64) {
+            description("image is not a derivative")
+            display("Image {} is an original, not a derivative", id)
+        }
+        HasDerivatives(id: i64) {
+            description("image still has derivatives")
+            display("Image {} still has derivatives", id)
+        }
+        ImageTooLarge { width: u32, height: u32, max: u32 } {
+            description("image is too large")
+            display("Image is {}x{} but at most {}px are allowed per side", width, height, max)
+        }
+        InvalidDimensions { width: i32, height: i32 } {
+            description("image dimensions have to be positive")
+            display("Invalid image dimensions: {}x{}", width, height)
+        }
+        ImageFileMissing(id: i64, path: String) {
+            description("image file is missing")
+            display("File {} of image {} is missing", path, id)
+        }
+        PathEscape(path: String) {
+            description("image path points outside of the upload directory")
+            display("Image path {} points outside of the upload directory", path)
+        }
+        CorruptBase64 { image_id: i64, err: ::rustc_serialize::base64::FromBase64Error } {
+            cause(err)
+            description("inline image data is not valid base64")
+            display("Inline data of image {} is not valid base64: {}", image_id, err)
+        }
+        InvalidImageFormat(format: i32) {
+            description("invalid image format")
+            display("Invalid image format: {}", format)
+        }
+        FormatMismatch { declared: ImageFormat, detected: UploadFormat } {
+            description("image is not in the format it claims to be")
+            display("Image was declared as {} but is {}", declared, detected)
+        }
+        FormatNotAllowed(format: UploadFormat) {
+            description("image format is not allowed")
+            display("Images in {} format are not accepted", format)
+        }
+        InvalidDataUri {
+            description("not a base64 encoded data URI")
+        }
+        UnknownImageFormat(name: String) {
+            description("unknown image format")
+            display("Unknown image format: {}", name)
+        }
+        InvalidImageType(typ: i32) {
+            description("invalid image type")
+            display("Invalid image type: {}", typ)
+        }
+        InvalidResizeMode(mode: i32) {
+            description("invalid resize mode")
+            display("Invalid resize mode: {}", mode)
+        }
+        InvalidTransform(transform: i32) {
+            description("invalid image transform")
+            display("Invalid image transform: {}", transform)
+        }
+        UnsupportedImageFormat(format: ::image::ImageFormat) {
+            description("unsupported image format")
+            display("Unsupported image format: {:?}", format)
+        }
+        InvalidQuality(quality: u8) {
+            description("encoding quality has to be between 1 and 100")
+            display("Invalid encoding quality: {}", quality)
+        }
+        EncoderUnavailable(format: &'static str) {
+            description("no encoder available for image format")
+            display("No encoder available for image format: {}", format)
+        }
         Parse(err: Option<Box<Error + Send>>) {
             from(e: ::std::num::ParseIntError) -> (Some(Box::new(e)))
             from(e: ::rustc_serialize::base64::FromBase64Error) -> (Some(Box::new(e)))
@@ -58,8 +187,50 @@ quick_error! {
     }
 }
 
+impl From<diesel::result::TransactionError<FurryError>> for FurryError {
+    fn from(e: diesel::result::TransactionError<FurryError>) -> FurryError {
+        use diesel::result::TransactionError::*;
+        match e {
+            CouldntCreateTransaction(e) => e.into(),
+            UserReturnedError(e) => e,
+        }
+    }
+}
+
+impl From<diesel::result::TransactionError<diesel::result::Error>> for FurryError {
+    fn from(e: diesel::result::TransactionError<diesel::result::Error>) -> FurryError {
+        use diesel::result::TransactionError::*;
+        match e {
+            CouldntCreateTransaction(e) | UserReturnedError(e) => e.into(),
+        }
+    }
+}
+
+/// Broken or unsupported images are the uploader's fault, IO errors are ours
+impl From<::image::ImageError> for FurryError {
+    fn from(e: ::image::ImageError) -> FurryError {
+        use image::ImageError::*;
+        match e {
+            IoError(e) => FurryError::Io(e),
+            e @ FormatError(_) | e @ DimensionError | e @ NotEnoughData | e @ ImageEnd => FurryError::Decode(e),
+            e @ UnsupportedError(_) | e @ UnsupportedColor(_) => FurryError::UnsupportedImage(e),
+            e => FurryError::Image(e),
+        }
+    }
+}
+
 impl From<FurryError> for IronError {
     fn from(e: FurryError) -> IronError {
-        IronError::new(Box::new(e), status::InternalServerError)
+        let code = match e {
+            FurryError::PoolTimeout(_) => status::ServiceUnavailable,
+            FurryError::DerivativeLimit { .. } => status::TooManyRequests,
+            FurryError::UploadTooLarge(_) => status::PayloadTooLarge,
+            FurryError::Decode(_) | FurryError::UnsupportedImage(_) => status::BadRequest,
+            FurryError::FormatMismatch { .. } | FurryError::FormatNotAllowed(_) => status::BadRequest,
+            FurryError::InvalidDataUri => status::BadRequest,
+            FurryError::FetchForbidden(_) => status::BadRequest,
+            _ => status::InternalServerError,
+        };
+        IronError::new(Box::new(e), code)
     }
 }
@@ -1,3 +1,5 @@
+use chrono::{DateTime, TimeZone, Timelike, Utc};
+use diesel::data_types::PgTimestamp;
 
 pub trait StringHelpers {
     fn possessive(&self) -> String;
@@ -12,3 +14,45 @@ impl<S: AsRef<str>> StringHelpers for S {
         })
     }
 }
+
+/// Seconds between the unix epoch and the postgres epoch (2000-01-01)
+const PG_EPOCH_OFFSET : i64 = 946_684_800;
+
+pub fn pg_timestamp_to_utc(ts: &PgTimestamp) -> DateTime<Utc> {
+    let mut secs = ts.0 / 1_000_000;
+    let mut micros = ts.0 % 1_000_000;
+    if micros < 0 {
+        secs -= 1;
+        micros += 1_000_000;
+    }
+    Utc.timestamp(secs + PG_EPOCH_OFFSET, (micros * 1000) as u32)
+}
+
+pub fn utc_to_pg_timestamp(dt: &DateTime<Utc>) -> PgTimestamp {
+    PgTimestamp((dt.timestamp() - PG_EPOCH_OFFSET) * 1_000_000 + (dt.nanosecond() / 1000) as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use diesel::data_types::PgTimestamp;
+    use super::*;
+
+    #[test]
+    fn pg_epoch_is_the_year_2000() {
+        assert_eq!(pg_timestamp_to_utc(&PgTimestamp(0)), Utc.ymd(2000, 1, 1).and_hms(0, 0, 0));
+        assert_eq!(pg_timestamp_to_utc(&PgTimestamp(86_400_000_001)), Utc.ymd(2000, 1, 2).and_hms_micro(0, 0, 0, 1));
+    }
+
+    #[test]
+    fn timestamps_before_the_pg_epoch_round_down() {
+        assert_eq!(pg_timestamp_to_utc(&PgTimestamp(-1)), Utc.ymd(1999, 12, 31).and_hms_micro(23, 59, 59, 999_999));
+    }
+
+    #[test]
+    fn pg_timestamps_round_trip() {
+        for &micros in &[0, 1, -1, 542_123_456_789, -542_123_456_789] {
+            assert_eq!(utc_to_pg_timestamp(&pg_timestamp_to_utc(&PgTimestamp(micros))), PgTimestamp(micros));
+        }
+    }
+}
@@ -1,12 +1,14 @@
 #![feature(plugin)]
 #![plugin(maud_macros)]
 #![feature(proc_macro)]
+#![feature(try_from)]
 #![plugin(dotenv_macros)]
 
 #![allow(dead_code)]
 
 
 #[macro_use] extern crate iron;
+extern crate hyper;
 extern crate router;
 extern crate mount;
 extern crate maud;
@@ -29,6 +31,14 @@ extern crate image;
 extern crate rand;
 extern crate rustc_serialize;
 extern crate lettre;
+extern crate sha2;
+extern crate serde;
+extern crate chrono;
+extern crate exif;
+extern crate rayon;
+extern crate gif;
+extern crate flate2;
+extern crate png;
 
 use std::env;
 use std::path::Path;
@@ -49,6 +59,7 @@ mod models;
 mod logger;
 mod middleware;
 mod helper;
+mod s3;
 
 fn main() {
     dotenv().ok();
@@ -175,8 +186,13 @@ fn main() {
          .mount("/logout",         logout_router)
          .mount("/password_reset", password_reset_router)
          .mount("/submissions",    sub_router)
+         .mount("/assets/uploads/", staticfile::Static::new(models::image::upload_dir()).cache(Duration::new(60 * 60 * 24 * 7, 0)))
          .mount("/assets/", staticfile::Static::new(Path::new("assets/")).cache(Duration::new(60 * 60 * 24 * 7, 0)));
 
+    if let Some(dir) = models::image::derivative_dir() {
+        mount.mount("/assets/derivatives/", staticfile::Static::new(dir).cache(Duration::new(60 * 60 * 24 * 7, 0)));
+    }
+
 
     let cookie_secret= env::var("COOKIE_SECRET")
         .expect("COOKIE_SECRET must be set").into_bytes();
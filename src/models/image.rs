@@ -1,244 +1,4778 @@
-use std::time::{SystemTime, UNIX_EPOCH};
-use std::fs::File;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::str::FromStr;
+use std::convert::TryFrom;
+use std::fmt;
 
 use diesel;
+use diesel::pg::PgConnection;
+use rand::{thread_rng, Rng};
 use image::{DynamicImage, GenericImage, self};
+use gif;
 use rustc_serialize::base64::{ToBase64, FromBase64, self};
+use rustc_serialize::hex::ToHex;
+use sha2::{Sha256, Digest};
+use serde::{Serialize, Serializer};
+use chrono::{DateTime, Utc};
 
 use models::schema::images;
 use database;
 use models;
 use error;
+use helper;
+use s3;
 
-#[repr(i32)]
-#[derive(Copy, Clone, Debug)]
-pub enum ImageType {
-    Local, Base64,
+lazy_static! {
+    /// Images smaller than this on both axes are stored inline as base64, `none` disables inlining
+    static ref INLINE_MAX_DIM: Option<u32> = {
+        use std::env;
+        match env::var("IMAGE_INLINE_MAX_DIM") {
+            Ok(ref s) if s == "none" => None,
+            Ok(s) => Some(s.parse().expect("IMAGE_INLINE_MAX_DIM must be a number or 'none'")),
+            Err(_) => Some(200),
+        }
+    };
+
+    /// Largest width or height we are willing to store or resize from
+    static ref MAX_DIM: u32 = {
+        use std::env;
+        env::var("IMAGE_MAX_DIM")
+            .map(|s| s.parse().expect("IMAGE_MAX_DIM must be a number"))
+            .unwrap_or(10000)
+    };
+
+    /// Directory that local image paths are resolved against, the stored paths stay relative to it
+    static ref UPLOAD_ROOT: PathBuf = {
+        use std::env;
+        env::var("IMAGE_UPLOAD_ROOT").map(PathBuf::from).unwrap_or_else(|_| PathBuf::from("."))
+    };
+
+    /// Sizes requests get snapped to when asked to, keeps the number of derivatives per image bounded
+    static ref SIZE_PRESETS: Vec<i32> = {
+        use std::env;
+        let mut presets = env::var("IMAGE_SIZE_PRESETS")
+            .map(|s| s.split(',').map(|p| p.trim().parse().expect("IMAGE_SIZE_PRESETS must be a list of numbers"))
+                      .collect::<Vec<i32>>())
+            .unwrap_or_else(|_| vec![64, 128, 256, 512, 1024]);
+        presets.sort();
+        presets
+    };
+
+    /// Writable directory derivatives go to when the upload directory is read only, unset keeps them next
+    /// to their originals
+    static ref DERIVATIVE_DIR: Option<PathBuf> = {
+        use std::env;
+        env::var("IMAGE_DERIVATIVE_DIR").ok().map(PathBuf::from)
+    };
+
+    /// Opaque PNGs whose encoding is larger than this many bytes get stored as JPEG instead, unset disables it
+    static ref PNG_TRANSCODE_MIN_BYTES: Option<usize> = {
+        use std::env;
+        env::var("IMAGE_PNG_TRANSCODE_MIN_BYTES").ok()
+            .map(|s| s.parse().expect("IMAGE_PNG_TRANSCODE_MIN_BYTES must be a number"))
+    };
+
+    /// What AVIF images get stored as while there is no encoder for it, WebP needs an encoder just the same
+    static ref AVIF_FALLBACK: ImageFormat = {
+        use std::env;
+        let format = env::var("IMAGE_AVIF_FALLBACK")
+            .map(|s| s.parse().expect("IMAGE_AVIF_FALLBACK must be an image format"))
+            .unwrap_or(ImageFormat::JPEG);
+        if !format.can_encode() {
+            panic!("IMAGE_AVIF_FALLBACK must be a format we can encode, there is no encoder for {}", format);
+        }
+        format
+    };
+
+    /// Most derivatives a single image may have, requests for further sizes fail, `none` lifts the limit
+    static ref MAX_DERIVATIVES: Option<i64> = {
+        use std::env;
+        match env::var("IMAGE_MAX_DERIVATIVES") {
+            Ok(ref s) if s == "none" => None,
+            Ok(s) => Some(s.parse().expect("IMAGE_MAX_DERIVATIVES must be a number or 'none'")),
+            Err(_) => Some(64),
+        }
+    };
+
+    /// Decoded images kept around for generating derivatives, at most `IMAGE_DECODE_CACHE_BYTES` worth of pixels,
+    /// 0 disables it
+    static ref DECODE_CACHE: Mutex<DecodeCache> = {
+        use std::env;
+        let capacity = env::var("IMAGE_DECODE_CACHE_BYTES")
+            .map(|s| s.parse().expect("IMAGE_DECODE_CACHE_BYTES must be a number"))
+            .unwrap_or(256 * 1024 * 1024);
+        Mutex::new(DecodeCache::new(capacity))
+    };
+
+    /// JPEG quality derivatives are encoded with, originals always use the encoder's default
+    static ref DERIVATIVE_QUALITY: Option<u8> = {
+        use std::env;
+        env::var("IMAGE_DERIVATIVE_QUALITY").ok()
+            .map(|s| s.parse().expect("IMAGE_DERIVATIVE_QUALITY must be a number between 1 and 100"))
+    };
+
+    /// zlib effort for PNG derivatives, `fast`, `default` or `best`, unset leaves them to the image crate
+    static ref DERIVATIVE_PNG_COMPRESSION: Option<PngCompression> = {
+        use std::env;
+        env::var("IMAGE_DERIVATIVE_PNG_COMPRESSION").ok().map(|s| match &s[..] {
+            "fast" => PngCompression::Fast,
+            "default" => PngCompression::Default,
+            "best" => PngCompression::Best,
+            _ => panic!("IMAGE_DERIVATIVE_PNG_COMPRESSION must be 'fast', 'default' or 'best', got '{}'", s),
+        })
+    };
+
+    /// Scanline filter for PNG derivatives, one of `none`, `sub`, `up`, `average`, `paeth` or `adaptive`
+    static ref DERIVATIVE_PNG_FILTER: Option<PngFilter> = {
+        use std::env;
+        env::var("IMAGE_DERIVATIVE_PNG_FILTER").ok().map(|s| match &s[..] {
+            "none" => PngFilter::None,
+            "sub" => PngFilter::Sub,
+            "up" => PngFilter::Up,
+            "average" => PngFilter::Average,
+            "paeth" => PngFilter::Paeth,
+            "adaptive" => PngFilter::Adaptive,
+            _ => panic!("IMAGE_DERIVATIVE_PNG_FILTER must be a PNG filter, got '{}'", s),
+        })
+    };
+
+    /// What transparency is composited over for formats that can't store it, as hex RGB
+    static ref BACKGROUND: (u8, u8, u8) = {
+        use std::env;
+        env::var("IMAGE_BACKGROUND").ok()
+            .map(|s| {
+                let hex = s.trim_left_matches('#');
+                match u32::from_str_radix(hex, 16) {
+                    Ok(rgb) if hex.len() == 6 => ((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8),
+                    _ => panic!("IMAGE_BACKGROUND must be a hex color like ffffff, got '{}'", s),
+                }
+            })
+            .unwrap_or((255, 255, 255))
+    };
+
+    /// Whether uploads that aren't the format they claim to be get stored as what they are (`correct`)
+    /// or turned away (`reject`)
+    static ref CORRECT_FORMAT_MISMATCH: bool = {
+        use std::env;
+        match env::var("IMAGE_FORMAT_MISMATCH") {
+            Ok(ref s) if s == "reject" => false,
+            Ok(ref s) if s == "correct" => true,
+            Ok(s) => panic!("IMAGE_FORMAT_MISMATCH must be 'reject' or 'correct', got '{}'", s),
+            Err(_) => false,
+        }
+    };
+
+    /// Formats uploads may come in, a comma separated list like `png,gif,jpeg,tiff` which is also the default
+    static ref ALLOWED_FORMATS: Vec<UploadFormat> = {
+        use std::env;
+        env::var("IMAGE_ALLOWED_FORMATS")
+            .map(|s| s.split(',').map(|f| f.trim().parse().expect("IMAGE_ALLOWED_FORMATS must be a list of image formats")).collect())
+            .unwrap_or_else(|_| vec![UploadFormat::Stored(ImageFormat::PNG), UploadFormat::Stored(ImageFormat::GIF),
+                                     UploadFormat::Stored(ImageFormat::JPEG), UploadFormat::TIFF])
+    };
+
+    /// Alphabet new inline images are encoded with, `urlsafe` avoids `+` and `/`
+    static ref BASE64_CHARSET: base64::CharacterSet = {
+        use std::env;
+        match env::var("IMAGE_BASE64_CHARSET") {
+            Ok(ref s) if s == "standard" => base64::CharacterSet::Standard,
+            Ok(ref s) if s == "urlsafe" => base64::CharacterSet::UrlSafe,
+            Ok(s) => panic!("IMAGE_BASE64_CHARSET must be 'standard' or 'urlsafe', got '{}'", s),
+            Err(_) => base64::CharacterSet::Standard,
+        }
+    };
 }
 
-impl ImageType {
-    pub fn from_i32(i: i32) -> ImageType {
-        match i {
-            0 => ImageType::Local,
-            1 => ImageType::Base64,
-            _ => panic!("tried to use out of bound image type")
+static UPLOAD_PATH : &'static str = "/assets/uploads";
+static DERIVATIVE_PATH : &'static str = "/assets/derivatives";
+/// Encoded images larger than this are never inlined, whatever their dimensions
+static INLINE_MAX_BYTES : usize = 32 * 1024;
+static FETCH_MAX_SIZE : u64 = 10 * 1024 * 1024; // 10 Megabytes
+static FETCH_TIMEOUT_SECS : u64 = 10;
+static FETCH_MAX_REDIRECTS : usize = 5;
+static UPLOAD_MAX_SIZE : u64 = 10 * 1024 * 1024; // 10 Megabytes
+static ACCESS_TOUCH_INTERVAL_SECS : i64 = 60 * 60;
+/// Smallest factor a source gets shrunk by while decoding, below that a regular decode is just as good
+static STREAMING_MIN_FACTOR : u32 = 2;
+
+/// Least recently used cache of decoded images by id, small enough that a linear search doesn't matter
+///
+/// It is bounded by the bytes of the pixels it holds, a handful of large photos weigh more than hundreds of avatars.
+struct DecodeCache {
+    /// In bytes
+    capacity: usize,
+    used: usize,
+    /// Most recently used last
+    entries: Vec<(i64, Arc<DynamicImage>)>,
+}
+
+/// Bytes taken up by the pixels of `img`
+fn decoded_size(img: &DynamicImage) -> usize {
+    let (w, h) = img.dimensions();
+    let channels = match *img {
+        DynamicImage::ImageLuma8(_) => 1,
+        DynamicImage::ImageLumaA8(_) => 2,
+        DynamicImage::ImageRgb8(_) => 3,
+        DynamicImage::ImageRgba8(_) => 4,
+    };
+    w as usize * h as usize * channels
+}
+
+impl DecodeCache {
+    fn new(capacity: usize) -> DecodeCache {
+        DecodeCache { capacity: capacity, used: 0, entries: Vec::new() }
+    }
+
+    fn get(&mut self, id: i64) -> Option<Arc<DynamicImage>> {
+        let pos = match self.entries.iter().position(|&(i, _)| i == id) {
+            Some(pos) => pos,
+            None => return None,
+        };
+        let entry = self.entries.remove(pos);
+        let img = entry.1.clone();
+        self.entries.push(entry);
+        Some(img)
+    }
+
+    /// Images larger than the whole cache aren't kept at all
+    fn insert(&mut self, id: i64, img: Arc<DynamicImage>) {
+        let size = decoded_size(&img);
+        if size > self.capacity {
+            return;
+        }
+        self.remove(id);
+        while self.used + size > self.capacity {
+            let (_, evicted) = self.entries.remove(0);
+            self.used -= decoded_size(&evicted);
+        }
+        self.used += size;
+        self.entries.push((id, img));
+    }
+
+    fn remove(&mut self, id: i64) {
+        if let Some(pos) = self.entries.iter().position(|&(i, _)| i == id) {
+            let (_, removed) = self.entries.remove(pos);
+            self.used -= decoded_size(&removed);
         }
     }
+
+    fn contains(&self, id: i64) -> bool {
+        self.entries.iter().any(|&(i, _)| i == id)
+    }
 }
 
-#[repr(i32)]
+/// Where newly uploaded files are written to and served from
+pub fn upload_dir() -> PathBuf {
+    local_path(UPLOAD_PATH)
+}
+
+/// Tags an IO error with the operation and the file it failed on
+fn file_error(op: &'static str, path: &Path, err: ::std::io::Error) -> error::FurryError {
+    error::FurryError::FileIo { op: op, path: path.display().to_string(), err: err }
+}
+
+/// Where derivatives are written to and served from, if they don't go into the upload directory
+pub fn derivative_dir() -> Option<PathBuf> {
+    DERIVATIVE_DIR.clone()
+}
+
+/// The store new local files get written to
 #[derive(Copy, Clone, Debug)]
-pub enum ImageFormat {
-    PNG, GIF, JPEG
+pub enum Store {
+    Uploads,
+    Derivatives,
 }
 
-impl ImageFormat {
-    pub fn from_i32(i: i32) -> ImageFormat {
-        match i {
-            0 => ImageFormat::PNG,
-            1 => ImageFormat::GIF,
-            2 => ImageFormat::JPEG,
-            _ => panic!("tried to use out of bound image format")
+impl Store {
+    /// Where in the bucket objects go when images are stored in S3
+    fn key_prefix(&self) -> &'static str {
+        match *self {
+            Store::Uploads => "uploads",
+            Store::Derivatives => "derivatives",
         }
     }
 
-    pub fn as_str(&self) -> &'static str {
-        match *self {
-            ImageFormat::PNG => "png",
-            ImageFormat::GIF => "gif",
-            ImageFormat::JPEG => "jpg",
+    /// The prefix of paths in this store and the directory they are in
+    fn location(&self) -> (&'static str, PathBuf) {
+        match (*self, DERIVATIVE_DIR.as_ref()) {
+            (Store::Derivatives, Some(dir)) => (DERIVATIVE_PATH, dir.clone()),
+            _ => (UPLOAD_PATH, upload_dir()),
         }
     }
+}
 
-    // Pretty shitty name
-    pub fn from_image_format(i: image::ImageFormat) -> ImageFormat {
-        match i {
-            image::PNG  => ImageFormat::PNG,
-            image::GIF  => ImageFormat::GIF,
-            image::JPEG => ImageFormat::JPEG,
-            _ => panic!("tried to use out of bound image format")
+fn local_path(path: &str) -> PathBuf {
+    UPLOAD_ROOT.join(path.trim_left_matches('/'))
+}
+
+/// The file behind a stored path and the directory of its store, without checking anything
+fn stored_file(path: &str) -> (PathBuf, PathBuf) {
+    stored_file_in(path, DERIVATIVE_DIR.as_ref())
+}
+
+fn stored_file_in(path: &str, derivative_dir: Option<&PathBuf>) -> (PathBuf, PathBuf) {
+    match derivative_dir {
+        Some(dir) if path.starts_with(&format!("{}/", DERIVATIVE_PATH)[..]) => {
+            (dir.join(path[DERIVATIVE_PATH.len()..].trim_left_matches('/')), dir.clone())
         }
+        _ => (local_path(path), upload_dir()),
+    }
+}
+
+/// Resolves a stored path to its file, making sure it doesn't point outside of the directory of its store
+fn resolve_local(path: &str) -> Result<PathBuf, error::FurryError> {
+    use std::path::Component;
+
+    let (full, base) = stored_file(path);
+    if !full.starts_with(&base) || full.components().any(|c| c == Component::ParentDir) {
+        return Err(error::FurryError::PathEscape(path.to_string()));
     }
 
-    pub fn as_image_format(&self) -> image::ImageFormat {
-        match *self {
-            ImageFormat::PNG => image::PNG,
-            ImageFormat::GIF => image::GIF,
-            ImageFormat::JPEG => image::JPEG,
+    // Symlinks could still lead out, a file that doesn't exist can't and gets reported as missing when opened
+    if let (Ok(resolved), Ok(base)) = (full.canonicalize(), base.canonicalize()) {
+        if !resolved.starts_with(&base) {
+            return Err(error::FurryError::PathEscape(path.to_string()));
         }
     }
+    Ok(full)
 }
 
-#[derive(Queryable, Identifiable, Clone)]
-pub struct Image {
-    pub id: i64,
-    pub created_at: diesel::data_types::PgTimestamp,
-    pub updated_at: diesel::data_types::PgTimestamp,
-    host_type: i32,
-    path: String,
-    pub width: i32,
-    pub height: i32,
-    pub parent_id: Option<i64>,
-    pub wanted_height: Option<i32>,
-    pub wanted_width:  Option<i32>,
-    format: i32,
+/// Writes to a temporary file next to the final one first and renames it into place once it's complete,
+/// so nobody ever gets to see half of an image under its final path
+fn write_atomically(dir: &Path, filename: &str, bytes: &[u8]) -> Result<PathBuf, error::FurryError> {
+    use std::fs;
+
+    let full = dir.join(filename);
+    let tmp = dir.join(format!(".{}.tmp", filename));
+
+    let mut file = try!(OpenOptions::new().write(true).create_new(true).open(&tmp)
+                        .map_err(|e| file_error("create", &tmp, e)));
+    let written = file.write_all(bytes).and_then(|_| file.sync_all())
+        .map_err(|e| file_error("write", &tmp, e))
+        .and_then(|_| fs::rename(&tmp, &full).map_err(|e| file_error("move", &full, e)));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    Ok(full)
 }
 
-impl Image {
-    pub fn create_from(new: NewImage) -> Result<i64, error::FurryError> {
-        use diesel;
-        use diesel::prelude::*;
-        use models::schema::images::dsl::*;
-        diesel::insert(&new).into(images)
-            .returning(id).get_result(&*database::connection().get().unwrap()).map_err(|e| e.into())
+/// Where the encoded bytes of an image live, rows only point to them through `host_type` and `path`
+pub trait ImageStore: Sync {
+    fn read(&self, img: &Image) -> Result<Vec<u8>, error::FurryError>;
+
+    /// A reader over the encoded image, unless a store can stream it is read at once
+    fn open(&self, img: &Image) -> Result<Box<Read>, error::FurryError> {
+        use std::io::Cursor;
+        Ok(Box::new(Cursor::new(try!(self.read(img)))))
     }
 
-    pub fn get_path(&self) -> String {
-        match ImageType::from_i32(self.host_type) {
-            ImageType::Local  => format!("{}", self.path),
-            ImageType::Base64 => format!("data:image/png;base64,{}", self.path),
+    /// Size of the encoded image in bytes, without reading it if possible
+    fn content_length(&self, img: &Image) -> Result<u64, error::FurryError>;
+
+    /// Where browsers can load the image from
+    fn url(&self, img: &Image) -> Result<String, error::FurryError>;
+
+    fn write(&self, bytes: &[u8], meta: &StoreMeta) -> Result<StoredRef, error::FurryError>;
+
+    /// Removes what got written to `path`, nothing being there anymore is fine
+    fn remove(&self, path: &str) -> Result<(), error::FurryError>;
+}
+
+/// What a store gets to know about an image it writes
+pub struct StoreMeta<'a> {
+    /// Unique among all images, stores with their own naming scheme can ignore it
+    pub filename: &'a str,
+    pub format: ImageFormat,
+    pub store: Store,
+}
+
+/// What ends up in the `host_type` and `path` of the row
+pub struct StoredRef {
+    pub host_type: ImageType,
+    pub path: String,
+}
+
+/// Files below `IMAGE_UPLOAD_ROOT` or `IMAGE_DERIVATIVE_DIR`
+pub struct LocalStore;
+/// Small images kept inline in the row itself
+pub struct Base64Store;
+/// Objects in the bucket configured with `S3_BUCKET`
+pub struct S3Store;
+
+static LOCAL_STORE : LocalStore = LocalStore;
+static BASE64_STORE : Base64Store = Base64Store;
+static S3_STORE : S3Store = S3Store;
+
+impl LocalStore {
+    /// The opened file together with where it actually is, which depends on the store it was written to
+    fn open_file(&self, img: &Image) -> Result<(File, PathBuf), error::FurryError> {
+        use std::io::ErrorKind;
+
+        let full = try!(resolve_local(&img.path));
+        match File::open(&full) {
+            Ok(f) => Ok((f, full)),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                Err(error::FurryError::ImageFileMissing(img.id, img.path.clone()))
+            }
+            Err(e) => Err(file_error("open", &full, e)),
         }
     }
+}
 
-    pub fn get_with_size(&self, width: i32, height: i32) -> Result<Image, error::FurryError> {
-        if self.width > width || self.height > height {
-            match find_from_image(self.id, width, height) {
-                Ok(Some(i)) => Ok(i),
-                Ok(None) => {
-                    let new_image = try!(NewImage::create_from_image_with_size(self, width, height));
-                    let img_id = try!(Image::create_from(new_image));
-                    find(img_id).map(|x| x.expect("Inserting couldn't have failed"))
-                }
-                Err(e) => Err(e),
-            }
-        } else {
-            Ok(self.clone())
+impl ImageStore for LocalStore {
+    fn read(&self, img: &Image) -> Result<Vec<u8>, error::FurryError> {
+        let (mut file, full) = try!(self.open_file(img));
+        let mut bytes = Vec::new();
+        try!(file.read_to_end(&mut bytes).map_err(|e| file_error("read", &full, e)));
+        Ok(bytes)
+    }
+
+    fn open(&self, img: &Image) -> Result<Box<Read>, error::FurryError> {
+        use std::io::BufReader;
+        Ok(Box::new(BufReader::new(try!(self.open_file(img)).0)))
+    }
+
+    fn content_length(&self, img: &Image) -> Result<u64, error::FurryError> {
+        use std::fs;
+
+        let full = try!(resolve_local(&img.path));
+        let meta = try!(fs::metadata(&full).map_err(|e| file_error("stat", &full, e)));
+        Ok(meta.len())
+    }
+
+    fn url(&self, img: &Image) -> Result<String, error::FurryError> {
+        try!(resolve_local(&img.path));
+        Ok(img.path.clone())
+    }
+
+    fn write(&self, bytes: &[u8], meta: &StoreMeta) -> Result<StoredRef, error::FurryError> {
+        let (prefix, dir) = meta.store.location();
+        try!(write_atomically(&dir, meta.filename, bytes));
+        Ok(StoredRef { host_type: ImageType::Local, path: format!("{}/{}", prefix, meta.filename) })
+    }
+
+    fn remove(&self, path: &str) -> Result<(), error::FurryError> {
+        use std::fs;
+        use std::io::ErrorKind;
+
+        let full = try!(resolve_local(path));
+        match fs::remove_file(&full) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            r => r.map_err(|e| file_error("remove", &full, e)),
         }
     }
+}
+
+impl ImageStore for Base64Store {
+    /// Rows from before the alphabet was configurable are standard encoded, either alphabet decodes fine
+    /// as `from_base64` accepts both
+    fn read(&self, img: &Image) -> Result<Vec<u8>, error::FurryError> {
+        img.path.from_base64().map_err(|e| error::FurryError::CorruptBase64 { image_id: img.id, err: e })
+    }
 
-    pub fn get_format(&self) -> ImageFormat {
-        ImageFormat::from_i32(self.format)
+    fn content_length(&self, img: &Image) -> Result<u64, error::FurryError> {
+        // Every 4 characters hold 3 bytes, padding and line breaks hold none
+        let chars = img.path.bytes().filter(|&b| b != b'=' && b != b'\n' && b != b'\r').count() as u64;
+        Ok(chars * 3 / 4)
+    }
+
+    fn url(&self, img: &Image) -> Result<String, error::FurryError> {
+        // Data URIs only take the standard alphabet, which has neither `-` nor `_`
+        Ok(format!("data:{};base64,{}", try!(img.get_format()).mime_type(),
+                   img.path.replace('-', "+").replace('_', "/")))
+    }
+
+    fn write(&self, bytes: &[u8], _meta: &StoreMeta) -> Result<StoredRef, error::FurryError> {
+        let path = bytes.to_base64(base64::Config {
+            char_set: *BASE64_CHARSET,
+            newline: base64::Newline::LF,
+            pad: true,
+            line_length: None,
+        });
+        Ok(StoredRef { host_type: ImageType::Base64, path: path })
+    }
+
+    fn remove(&self, _path: &str) -> Result<(), error::FurryError> {
+        Ok(())
     }
 }
 
-#[derive(Clone, Debug)]
-#[derive(Insertable)]
-#[table_name="images"]
-pub struct NewImage {
-    host_type: i32,
-    path: String,
-    width: i32,
-    height: i32,
-    parent_id: Option<i64>,
-    wanted_height: Option<i32>,
-    wanted_width:  Option<i32>,
-    format: i32,
+impl ImageStore for S3Store {
+    fn read(&self, img: &Image) -> Result<Vec<u8>, error::FurryError> {
+        s3::get(&img.path)
+    }
+
+    fn open(&self, img: &Image) -> Result<Box<Read>, error::FurryError> {
+        Ok(Box::new(try!(s3::open(&img.path))))
+    }
+
+    fn content_length(&self, img: &Image) -> Result<u64, error::FurryError> {
+        Ok(img.byte_size as u64)
+    }
+
+    fn url(&self, img: &Image) -> Result<String, error::FurryError> {
+        s3::object_url(&img.path)
+    }
+
+    fn write(&self, bytes: &[u8], meta: &StoreMeta) -> Result<StoredRef, error::FurryError> {
+        let key = format!("{}/{}", meta.store.key_prefix(), meta.filename);
+        try!(s3::put(&key, bytes, meta.format.mime_type()));
+        Ok(StoredRef { host_type: ImageType::S3, path: key })
+    }
+
+    fn remove(&self, path: &str) -> Result<(), error::FurryError> {
+        s3::delete(path)
+    }
 }
 
-impl NewImage {
-    pub fn new(typ: ImageType, path: &str) -> NewImage {
-        NewImage {
-            host_type: typ as i32,
-            path: path.to_string(),
-            width: 0,
-            height: 0,
-            parent_id: None,
-            wanted_height: None,
-            wanted_width:  None,
-            format: 0,
-        }
+fn check_dimensions(width: u32, height: u32) -> Result<(), error::FurryError> {
+    if width > *MAX_DIM || height > *MAX_DIM {
+        return Err(error::FurryError::ImageTooLarge { width: width, height: height, max: *MAX_DIM });
+    }
+    Ok(())
+}
+
+fn check_requested_size(width: i32, height: i32) -> Result<(), error::FurryError> {
+    if width <= 0 || height <= 0 {
+        return Err(error::FurryError::InvalidDimensions { width: width, height: height });
     }
+    Ok(())
+}
 
-    pub fn create_from_image_with_size(img: &Image, width: i32, height: i32) -> Result<NewImage, error::FurryError> {
-        let image = {
-            match ImageType::from_i32(img.host_type) {
-                ImageType::Local => {
-                    use std::io::BufReader;
-                    let f = try!(File::open(&format!(".{}", img.get_path())[..]));
-                    let f = BufReader::new(f);
-                    try!(image::load(f, img.get_format().as_image_format()))
-                },
-                ImageType::Base64 => {
-                    let bytes = try!(img.path.from_base64());
-                    try!(image::load_from_memory(&bytes[..]))
+/// Applies the EXIF orientation of the JPEG in `bytes` to its decoded pixels
+///
+/// The tag itself doesn't have to be stripped, encoding from the pixels never writes EXIF data.
+pub fn orient(img: DynamicImage, bytes: &[u8]) -> DynamicImage {
+    use std::io::Cursor;
+    use exif::{Reader, Tag};
+
+    if image::guess_format(bytes).ok() != Some(image::JPEG) {
+        return img;
+    }
+
+    let orientation = Reader::new(&mut Cursor::new(bytes)).ok()
+        .and_then(|r| r.get_field(Tag::Orientation, false).and_then(|f| f.value.get_uint(0)));
+
+    match orientation {
+        Some(2) => img.fliph(),
+        Some(3) => img.rotate180(),
+        Some(4) => img.flipv(),
+        Some(5) => img.rotate90().fliph(),
+        Some(6) => img.rotate90(),
+        Some(7) => img.rotate270().fliph(),
+        Some(8) => img.rotate270(),
+        _ => img,
+    }
+}
+
+/// When the JPEG in `bytes` was taken according to its EXIF `DateTimeOriginal`
+///
+/// EXIF doesn't record a time zone, the camera's local time is taken as UTC.
+pub fn captured_at(bytes: &[u8]) -> Option<diesel::data_types::PgTimestamp> {
+    use std::io::Cursor;
+    use std::str;
+    use chrono::NaiveDateTime;
+    use exif::{Reader, Tag, Value};
+
+    if image::guess_format(bytes).ok() != Some(image::JPEG) {
+        return None;
+    }
+
+    let reader = match Reader::new(&mut Cursor::new(bytes)) {
+        Ok(r) => r,
+        Err(_) => return None,
+    };
+    let raw = match reader.get_field(Tag::DateTimeOriginal, false).map(|f| &f.value) {
+        Some(&Value::Ascii(ref parts)) if !parts.is_empty() => parts[0],
+        _ => return None,
+    };
+
+    str::from_utf8(raw).ok()
+        .and_then(|s| NaiveDateTime::parse_from_str(s.trim_right_matches('\0'), "%Y:%m:%d %H:%M:%S").ok())
+        .map(|dt| helper::utc_to_pg_timestamp(&DateTime::<Utc>::from_utc(dt, Utc)))
+}
+
+/// The format an image decoded from `fmt` gets stored as, formats we only accept as input map to PNG
+pub fn storage_format(fmt: image::ImageFormat) -> image::ImageFormat {
+    match fmt {
+        // Scans come in as TIFF, which browsers can't show
+        image::TIFF => image::PNG,
+        fmt => fmt,
+    }
+}
+
+/// Whether any pixel is not fully opaque, images without an alpha channel never are
+pub fn has_alpha(img: &DynamicImage) -> bool {
+    match *img {
+        DynamicImage::ImageRgba8(ref buf) => buf.pixels().any(|p| p.data[3] != 255),
+        DynamicImage::ImageLumaA8(ref buf) => buf.pixels().any(|p| p.data[1] != 255),
+        _ => false,
+    }
+}
+
+/// Drops every metadata block from encoded image bytes so nothing like EXIF GPS positions gets stored
+///
+/// We always encode from decoded pixels, which shouldn't carry any metadata over, this makes sure of it.
+fn strip_metadata(bytes: Vec<u8>, format: ImageFormat) -> Vec<u8> {
+    let stripped = match format {
+        ImageFormat::JPEG => strip_jpeg_metadata(&bytes),
+        ImageFormat::PNG => strip_png_metadata(&bytes),
+        _ => None,
+    };
+    stripped.unwrap_or(bytes)
+}
+
+fn strip_jpeg_metadata(bytes: &[u8]) -> Option<Vec<u8>> {
+    if bytes.len() < 2 || bytes[0] != 0xFF || bytes[1] != 0xD8 {
+        return None;
+    }
+
+    let mut out = vec![0xFF, 0xD8];
+    let mut pos = 2;
+    while pos + 1 < bytes.len() {
+        if bytes[pos] != 0xFF {
+            return None;
+        }
+        let marker = bytes[pos + 1];
+        match marker {
+            // Start of scan, only entropy coded data follows
+            0xDA => {
+                out.extend_from_slice(&bytes[pos..]);
+                return Some(out);
+            }
+            0x01 | 0xD0...0xD7 => {
+                out.extend_from_slice(&bytes[pos..pos + 2]);
+                pos += 2;
+            }
+            _ => {
+                if pos + 3 >= bytes.len() {
+                    return None;
+                }
+                let len = ((bytes[pos + 2] as usize) << 8) | bytes[pos + 3] as usize;
+                let end = pos + 2 + len;
+                if end > bytes.len() {
+                    return None;
+                }
+                // APP1 to APP15 hold EXIF, XMP and friends, 0xFE is a comment
+                if !(marker >= 0xE1 && marker <= 0xEF) && marker != 0xFE {
+                    out.extend_from_slice(&bytes[pos..end]);
                 }
+                pos = end;
             }
-        };
+        }
+    }
+    Some(out)
+}
 
-        let mut image = try!(
-            NewImage::create_from_dynamic_image(&image.resize(width as u32, height as u32, image::FilterType::Lanczos3),
-                                                &format!("orig_{}", img.id)[..], img.get_format().as_image_format())
-        );
-        image.parent_id = Some(img.id);
-        image.wanted_height = Some(height);
-        image.wanted_width = Some(width);
-        Ok(image)
+fn strip_png_metadata(bytes: &[u8]) -> Option<Vec<u8>> {
+    static SIGNATURE : [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+    static METADATA_CHUNKS : [&'static [u8]; 5] = [b"tEXt", b"zTXt", b"iTXt", b"eXIf", b"tIME"];
+
+    if bytes.len() < 8 || bytes[..8] != SIGNATURE[..] {
+        return None;
     }
 
-    pub fn create_from_dynamic_image(img: &DynamicImage, suffix: &str, fmt: image::ImageFormat) -> Result<NewImage, error::FurryError> {
-        let dims = img.dimensions();
-        let mut path;
-        let typ;
-
-        if dims.0 < 200 && dims.1 < 200 {
-            let mut buf = Vec::new();
-            try!(img.save(&mut buf, image::PNG));
-            path = buf.to_base64(base64::Config {
-                char_set: base64::CharacterSet::Standard,
-                newline: base64::Newline::LF,
-                pad: true,
-                line_length: None,
-            });
-            typ = ImageType::Base64 as i32;
-        } else {
-            path = format!("./assets/uploads/{}_{}-{}-{}.{}",
-                           dims.0, dims.1,
-                           SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(), suffix,
-                           ImageFormat::from_image_format(fmt).as_str());
-            let mut file = try!(File::create(&path));
-            try!(img.save(&mut file, fmt));
-            typ = ImageType::Local as i32;
-            path = String::from(&path[1..]);
+    let mut out = SIGNATURE.to_vec();
+    let mut pos = 8;
+    while pos < bytes.len() {
+        if pos + 8 > bytes.len() {
+            return None;
+        }
+        let len = ((bytes[pos] as usize) << 24) | ((bytes[pos + 1] as usize) << 16)
+                | ((bytes[pos + 2] as usize) << 8) | bytes[pos + 3] as usize;
+        // length, type, data and crc
+        let end = pos + 12 + len;
+        if end > bytes.len() {
+            return None;
+        }
+        let typ = &bytes[pos + 4..pos + 8];
+        if !METADATA_CHUNKS.iter().any(|c| *c == typ) {
+            out.extend_from_slice(&bytes[pos..end]);
         }
+        pos = end;
+    }
+    Some(out)
+}
 
-        Ok(NewImage {
-            path: path,
-            host_type: typ,
-            width: dims.0 as i32,
-            height: dims.1 as i32,
-            parent_id: None,
-            wanted_height: None,
-            wanted_width: None,
-            format: ImageFormat::from_image_format(fmt) as i32,
-        })
+/// Encodes `img`, options for other formats than `fmt` are ignored
+fn encode(img: &DynamicImage, fmt: image::ImageFormat, opts: EncodeOptions) -> Result<Vec<u8>, error::FurryError> {
+    let mut buf = Vec::new();
+    match opts.jpeg_quality {
+        Some(q) if q < 1 || q > 100 => return Err(error::FurryError::InvalidQuality(q)),
+        _ if fmt == image::ICO => return encode_ico(img),
+        _ if fmt == image::BMP => return Ok(encode_bmp(img)),
+        _ if fmt == image::PNG && (opts.png_compression.is_some() || opts.png_filter.is_some()) => {
+            return encode_png(img, opts.png_compression.unwrap_or(PngCompression::Default),
+                              opts.png_filter.unwrap_or(PngFilter::None));
+        }
+        Some(q) if fmt == image::JPEG => {
+            let (w, h) = img.dimensions();
+            try!(image::jpeg::JPEGEncoder::new_with_quality(&mut buf, q).encode(&img.raw_pixels(), w, h, img.color()));
+        }
+        _ => try!(img.save(&mut buf, fmt)),
     }
+    Ok(buf)
 }
 
-pub fn find(uid: i64) -> Result<Option<Image>, error::FurryError> {
-    use diesel::prelude::*;
-    use models::schema::images::dsl::*;
+/// Our own PNG encoder, the one of the image crate has no say in compression or filtering
+fn encode_png(img: &DynamicImage, compression: PngCompression, filter: PngFilter) -> Result<Vec<u8>, error::FurryError> {
+    use flate2::Compression;
+    use flate2::write::ZlibEncoder;
 
-    images.limit(1).filter(id.eq(uid))
-         .get_result::<models::image::Image>(&*database::connection().get().unwrap()).optional().map_err(|e| e.into())
+    fn push_u32(buf: &mut Vec<u8>, v: u32) {
+        buf.extend_from_slice(&[(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8]);
+    }
+
+    fn chunk(buf: &mut Vec<u8>, kind: &[u8], data: &[u8]) {
+        push_u32(buf, data.len() as u32);
+        let start = buf.len();
+        buf.extend_from_slice(kind);
+        buf.extend_from_slice(data);
+        let crc = crc32(&buf[start..]);
+        push_u32(buf, crc);
+    }
+
+    let (color_type, channels) = match img.color() {
+        image::ColorType::Gray(8) => (0, 1),
+        image::ColorType::GrayA(8) => (4, 2),
+        image::ColorType::RGB(8) => (2, 3),
+        image::ColorType::RGBA(8) => (6, 4),
+        _ => return encode_png(&DynamicImage::ImageRgba8(img.to_rgba()), compression, filter),
+    };
+
+    let (w, h) = img.dimensions();
+    let pixels = img.raw_pixels();
+    let stride = w as usize * channels;
+    let zero = vec![0; stride];
+
+    let mut encoder = ZlibEncoder::new(Vec::new(), match compression {
+        PngCompression::Fast => Compression::Fast,
+        PngCompression::Default => Compression::Default,
+        PngCompression::Best => Compression::Best,
+    });
+    for y in 0..h as usize {
+        let line = &pixels[y * stride..(y + 1) * stride];
+        let prev = if y == 0 { &zero[..] } else { &pixels[(y - 1) * stride..y * stride] };
+        // The usual heuristic, the filtered line with the smallest sum of absolute differences compresses best
+        let filtered = filter.kinds().iter()
+            .map(|&kind| filter_scanline(kind, line, prev, channels))
+            .min_by_key(|l| l[1..].iter().map(|&b| (b as i8 as i32).abs()).sum::<i32>())
+            .unwrap();
+        try!(encoder.write_all(&filtered));
+    }
+    let data = try!(encoder.finish());
+
+    let mut header = Vec::with_capacity(13);
+    push_u32(&mut header, w);
+    push_u32(&mut header, h);
+    header.extend_from_slice(&[8, color_type, 0, 0, 0]);
+
+    let mut buf = Vec::with_capacity(data.len() + 57);
+    buf.extend_from_slice(b"\x89PNG\r\n\x1a\n");
+    chunk(&mut buf, b"IHDR", &header);
+    chunk(&mut buf, b"IDAT", &data);
+    chunk(&mut buf, b"IEND", &[]);
+    Ok(buf)
 }
 
-pub fn find_from_image(uid: i64, w: i32, h: i32) -> Result<Option<Image>, error::FurryError> {
-    use diesel::prelude::*;
-    use models::schema::images::dsl::*;
+/// Applies the PNG filter `kind` to `line`, prefixed with the filter byte
+fn filter_scanline(kind: u8, line: &[u8], prev: &[u8], bpp: usize) -> Vec<u8> {
+    fn paeth(a: u8, b: u8, c: u8) -> u8 {
+        let p = a as i16 + b as i16 - c as i16;
+        let (pa, pb, pc) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+        if pa <= pb && pa <= pc { a } else if pb <= pc { b } else { c }
+    }
 
-    images.limit(1)
-        .filter(parent_id.eq(uid))
-        .filter(
-            wanted_width.is_null().and(
-                width.eq(w).or(height.eq(h))
-             )
-            .or(
-                wanted_width.eq(w).or(wanted_height.eq(h))
-            )
-        )
-        .order(width.desc())
-        .order(height.desc())
-        .get_result::<models::image::Image>(&*database::connection().get().unwrap())
-        .optional().map_err(|e| e.into())
+    let mut out = Vec::with_capacity(line.len() + 1);
+    out.push(kind);
+    for i in 0..line.len() {
+        let a = if i >= bpp { line[i - bpp] } else { 0 };
+        let c = if i >= bpp { prev[i - bpp] } else { 0 };
+        let b = prev[i];
+        let predicted = match kind {
+            1 => a,
+            2 => b,
+            3 => ((a as u16 + b as u16) / 2) as u8,
+            4 => paeth(a, b, c),
+            _ => 0,
+        };
+        out.push(line[i].wrapping_sub(predicted));
+    }
+    out
 }
 
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Encodes `img` the way it gets stored, returning the format that was actually used
+fn encode_for_storage(img: &DynamicImage, format: ImageFormat, opts: EncodeOptions)
+    -> Result<(ImageFormat, Vec<u8>), error::FurryError>
+{
+    let (w, h) = img.dimensions();
+    try!(check_dimensions(w, h));
+
+    let format = try!(encodable_format(format));
+    let encoded = if !format.supports_alpha() && has_alpha(img) {
+        try!(encode(&flatten_alpha(img, opts.background()), try!(format.as_image_format()), opts))
+    } else {
+        try!(encode(img, try!(format.as_image_format()), opts))
+    };
+    Ok((format, strip_metadata(encoded, format)))
+}
+
+/// The format an image asked to be stored as actually gets encoded in
+fn encodable_format(format: ImageFormat) -> Result<ImageFormat, error::FurryError> {
+    let format = match format {
+        ImageFormat::AVIF if !format.can_encode() => {
+            warn!("No AVIF encoder available, storing as {} instead", *AVIF_FALLBACK);
+            *AVIF_FALLBACK
+        }
+        format => format,
+    };
+    if !format.can_encode() {
+        return Err(error::FurryError::EncoderUnavailable(format.as_str()));
+    }
+    Ok(format)
+}
+
+/// Composites `img` over `background`, for formats without an alpha channel
+pub fn flatten_alpha(img: &DynamicImage, background: (u8, u8, u8)) -> DynamicImage {
+    use image::{ImageBuffer, Rgb, RgbImage};
+
+    let rgba = img.to_rgba();
+    let (w, h) = rgba.dimensions();
+    let mut out: RgbImage = ImageBuffer::new(w, h);
+    for (x, y, p) in rgba.enumerate_pixels() {
+        let a = p.data[3] as u32;
+        let blend = |c: u8, bg: u8| ((c as u32 * a + bg as u32 * (255 - a) + 127) / 255) as u8;
+        out.put_pixel(x, y, Rgb { data: [blend(p.data[0], background.0),
+                                         blend(p.data[1], background.1),
+                                         blend(p.data[2], background.2)] });
+    }
+    DynamicImage::ImageRgb8(out)
+}
+
+/// Decodes the PNG in `bytes` shrunk by `factor` on both axes, each `factor`x`factor` block becomes its average
+///
+/// Only a single row of the full image is held in memory at a time. PNGs the decoder can't hand out row by row
+/// as 8 bit pixels, like interlaced ones, give `None` so the caller can fall back to decoding all of it.
+fn decode_png_downscaled(bytes: &[u8], factor: u32) -> Option<DynamicImage> {
+    use image::ImageBuffer;
+    use png::{BitDepth, ColorType, Decoder};
+
+    let (_, mut reader) = match Decoder::new(bytes).read_info() {
+        Ok(r) => r,
+        Err(_) => return None,
+    };
+    let (width, height, interlaced) = {
+        let info = reader.info();
+        (info.width, info.height, info.interlaced)
+    };
+    let (color, depth) = reader.output_color_type();
+    let channels = match color {
+        ColorType::Grayscale => 1,
+        ColorType::GrayscaleAlpha => 2,
+        ColorType::RGB => 3,
+        ColorType::RGBA => 4,
+        _ => return None,
+    };
+    let (out_w, out_h) = (width / factor, height / factor);
+    if interlaced || depth != BitDepth::Eight || out_w == 0 || out_h == 0 {
+        return None;
+    }
+
+    // Leftover rows and columns that don't fill a whole block are dropped
+    let area = (factor * factor) as u64;
+    let mut sums = vec![0u64; (out_w * channels) as usize];
+    let mut out = Vec::with_capacity(sums.len() * out_h as usize);
+    for y in 0..out_h * factor {
+        let row = match reader.next_row() {
+            Ok(Some(row)) => row,
+            _ => return None,
+        };
+        for x in 0..(out_w * factor) as usize {
+            let block = x / factor as usize * channels as usize;
+            for c in 0..channels as usize {
+                sums[block + c] += row[x * channels as usize + c] as u64;
+            }
+        }
+        if (y + 1) % factor == 0 {
+            out.extend(sums.iter().map(|s| ((s + area / 2) / area) as u8));
+            for s in sums.iter_mut() {
+                *s = 0;
+            }
+        }
+    }
+
+    match color {
+        ColorType::Grayscale => ImageBuffer::from_raw(out_w, out_h, out).map(DynamicImage::ImageLuma8),
+        ColorType::GrayscaleAlpha => ImageBuffer::from_raw(out_w, out_h, out).map(DynamicImage::ImageLumaA8),
+        ColorType::RGB => ImageBuffer::from_raw(out_w, out_h, out).map(DynamicImage::ImageRgb8),
+        _ => ImageBuffer::from_raw(out_w, out_h, out).map(DynamicImage::ImageRgba8),
+    }
+}
+
+/// Wraps the image as PNG into an ICO container with a single entry, which is how browsers like their favicons
+fn encode_ico(img: &DynamicImage) -> Result<Vec<u8>, error::FurryError> {
+    static MAX_ICO_DIM : u32 = 256;
+
+    let (w, h) = img.dimensions();
+    if w > MAX_ICO_DIM || h > MAX_ICO_DIM {
+        return Err(error::FurryError::ImageTooLarge { width: w, height: h, max: MAX_ICO_DIM });
+    }
+
+    let mut png = Vec::new();
+    try!(img.save(&mut png, image::PNG));
+    let len = png.len() as u32;
+
+    let mut buf = Vec::with_capacity(22 + png.len());
+    // Header: reserved, type 1 for icons and the number of images
+    buf.extend_from_slice(&[0, 0, 1, 0, 1, 0]);
+    // Directory entry, a size of 0 stands for 256
+    buf.extend_from_slice(&[w as u8, h as u8, 0, 0, 1, 0, 32, 0]);
+    buf.extend_from_slice(&[len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8]);
+    // The image data starts right after header and entry
+    buf.extend_from_slice(&[22, 0, 0, 0]);
+    buf.extend_from_slice(&png);
+    Ok(buf)
+}
+
+/// Uncompressed 24 bit BMP, alpha gets dropped as not every reader understands the 32 bit variants
+fn encode_bmp(img: &DynamicImage) -> Vec<u8> {
+    fn push_u16(buf: &mut Vec<u8>, v: u16) {
+        buf.extend_from_slice(&[v as u8, (v >> 8) as u8]);
+    }
+
+    fn push_u32(buf: &mut Vec<u8>, v: u32) {
+        buf.extend_from_slice(&[v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]);
+    }
+
+    let (w, h) = img.dimensions();
+    let rgb = img.to_rgb();
+    // Rows are padded to multiples of 4 bytes
+    let row = (w * 3 + 3) / 4 * 4;
+    let data_len = row * h;
+
+    let mut buf = Vec::with_capacity(54 + data_len as usize);
+    buf.extend_from_slice(b"BM");
+    push_u32(&mut buf, 54 + data_len);
+    push_u32(&mut buf, 0);
+    push_u32(&mut buf, 54);
+
+    // BITMAPINFOHEADER, a positive height means the rows are stored bottom up
+    push_u32(&mut buf, 40);
+    push_u32(&mut buf, w);
+    push_u32(&mut buf, h);
+    push_u16(&mut buf, 1);
+    push_u16(&mut buf, 24);
+    push_u32(&mut buf, 0);
+    push_u32(&mut buf, data_len);
+    // 72 DPI
+    push_u32(&mut buf, 2835);
+    push_u32(&mut buf, 2835);
+    push_u32(&mut buf, 0);
+    push_u32(&mut buf, 0);
+
+    for y in (0..h).rev() {
+        for x in 0..w {
+            let p = rgb.get_pixel(x, y).data;
+            buf.extend_from_slice(&[p[2], p[1], p[0]]);
+        }
+        for _ in w * 3..row {
+            buf.push(0);
+        }
+    }
+    buf
+}
+
+/// Hex encoded sha256 over the dimensions and decoded pixels, so re-encoding the same image keeps its hash
+pub fn content_hash(img: &DynamicImage) -> String {
+    let (w, h) = img.dimensions();
+    let mut hasher = Sha256::default();
+    hasher.input(format!("{}x{}", w, h).as_bytes());
+    hasher.input(&img.raw_pixels());
+    hasher.result().to_hex()
+}
+
+/// SHA-256 of encoded bytes as hex, see `Image::hashed_filename`
+fn bytes_hash(bytes: &[u8]) -> String {
+    let mut hasher = Sha256::default();
+    hasher.input(bytes);
+    hasher.result().to_hex()
+}
+
+/// The format of `bytes` going by their magic bytes, which has to be the `declared` one unless
+/// `IMAGE_FORMAT_MISMATCH` says to go with what was detected
+pub fn detect_format(bytes: &[u8], declared: ImageFormat) -> Result<image::ImageFormat, error::FurryError> {
+    let fmt = try!(image::guess_format(bytes));
+    let detected = try!(UploadFormat::from_image_format(fmt));
+    if detected != UploadFormat::Stored(declared) {
+        if !*CORRECT_FORMAT_MISMATCH {
+            return Err(error::FurryError::FormatMismatch { declared: declared, detected: detected });
+        }
+        info!("Upload declared as {} is {}, storing it as such", declared, detected);
+    }
+    try!(check_allowed(detected));
+    Ok(fmt)
+}
+
+/// The format of `bytes` going by their magic bytes, if uploads may come in it
+pub fn guess_allowed_format(bytes: &[u8]) -> Result<image::ImageFormat, error::FurryError> {
+    let fmt = try!(image::guess_format(bytes));
+    try!(check_allowed(try!(UploadFormat::from_image_format(fmt))));
+    Ok(fmt)
+}
+
+/// Fails for formats `IMAGE_ALLOWED_FORMATS` doesn't let in
+fn check_allowed(format: UploadFormat) -> Result<(), error::FurryError> {
+    if ALLOWED_FORMATS.contains(&format) {
+        Ok(())
+    } else {
+        Err(error::FurryError::FormatNotAllowed(format))
+    }
+}
+
+/// Fails unless `url` is http(s) and every address its host resolves to is a public one, so clients can't have us
+/// fetch from localhost, the internal network or the cloud metadata endpoint
+///
+/// The host gets resolved once more when connecting, a DNS server changing its answer in between isn't caught.
+fn check_fetch_target(url: &::hyper::Url) -> Result<(), error::FurryError> {
+    use std::net::ToSocketAddrs;
+
+    let forbidden = || error::FurryError::FetchForbidden(url.to_string());
+    match url.scheme() {
+        "http" | "https" => {}
+        _ => return Err(forbidden()),
+    }
+    let host = match url.host_str() {
+        Some(h) => h.trim_left_matches('[').trim_right_matches(']'),
+        None => return Err(forbidden()),
+    };
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let mut resolved = false;
+    for addr in try!((host, port).to_socket_addrs()) {
+        if !is_public_address(addr.ip()) {
+            return Err(forbidden());
+        }
+        resolved = true;
+    }
+    if resolved { Ok(()) } else { Err(forbidden()) }
+}
+
+fn is_public_address(ip: ::std::net::IpAddr) -> bool {
+    use std::net::IpAddr;
+
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            // 0.0.0.0/8 and the 100.64.0.0/10 carrier grade NAT range have no helper of their own
+            !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_broadcast() || ip.is_multicast()
+              || ip.is_unspecified() || octets[0] == 0 || (octets[0] == 100 && octets[1] & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(v4) = ip.to_ipv4() {
+                return is_public_address(IpAddr::V4(v4));
+            }
+            // Unique local fc00::/7 and link local fe80::/10
+            let first = ip.segments()[0];
+            !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast()
+              || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+/// Whether `bytes` are a GIF with more than one frame
+pub fn is_animated_gif(bytes: &[u8]) -> Result<bool, error::FurryError> {
+    use gif::SetParameter;
+
+    if image::guess_format(bytes).ok() != Some(image::GIF) {
+        return Ok(false);
+    }
+
+    let mut decoder = gif::Decoder::new(bytes);
+    decoder.set(gif::ColorOutput::Indexed);
+    let mut reader = try!(decoder.read_info());
+    let mut frames = 0;
+    while try!(reader.read_next_frame()).is_some() {
+        frames += 1;
+        if frames > 1 {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Resizes every frame of an animated GIF, returns the new GIF together with its dimensions and content hash
+///
+/// The `image` crate only ever decodes the first frame, so this goes through the `gif` crate directly.
+fn resize_animated_gif(bytes: &[u8], width: u32, height: u32, opts: ResizeOptions)
+    -> Result<(Vec<u8>, (u32, u32), String), error::FurryError>
+{
+    use gif::SetParameter;
+    use image::{ImageBuffer, Rgba, RgbaImage};
+
+    let mut decoder = gif::Decoder::new(bytes);
+    decoder.set(gif::ColorOutput::RGBA);
+    let mut reader = try!(decoder.read_info());
+    let (cw, ch) = (reader.width() as u32, reader.height() as u32);
+
+    // Frames can cover just a part of the image, they are drawn onto the full canvas before resizing
+    let mut canvas: RgbaImage = ImageBuffer::new(cw, ch);
+    let mut frames = Vec::new();
+    while let Some(frame) = try!(reader.read_next_frame()) {
+        let previous = canvas.clone();
+        let (left, top) = (frame.left as u32, frame.top as u32);
+        for (i, px) in frame.buffer.chunks(4).enumerate() {
+            let (x, y) = (left + i as u32 % frame.width as u32, top + i as u32 / frame.width as u32);
+            if px[3] != 0 && x < cw && y < ch {
+                canvas.put_pixel(x, y, Rgba([px[0], px[1], px[2], px[3]]));
+            }
+        }
+
+        frames.push((opts.apply(&DynamicImage::ImageRgba8(canvas.clone()), width, height), frame.delay));
+
+        match frame.dispose {
+            gif::DisposalMethod::Background => {
+                for y in top..(top + frame.height as u32).min(ch) {
+                    for x in left..(left + frame.width as u32).min(cw) {
+                        canvas.put_pixel(x, y, Rgba([0, 0, 0, 0]));
+                    }
+                }
+            }
+            gif::DisposalMethod::Previous => canvas = previous,
+            _ => {}
+        }
+    }
+
+    if frames.is_empty() {
+        return Err(error::FurryError::Gif(gif::DecodingError::Format("GIF without any frames")));
+    }
+
+    let (w, h) = frames[0].0.dimensions();
+    let mut hasher = Sha256::default();
+    hasher.input(format!("{}x{}", w, h).as_bytes());
+
+    let mut buf = Vec::new();
+    {
+        let mut encoder = try!(gif::Encoder::new(&mut buf, w as u16, h as u16, &[]));
+        try!(encoder.set(gif::Repeat::Infinite));
+        for (img, delay) in frames {
+            let mut pixels = img.to_rgba().into_raw();
+            hasher.input(&pixels);
+            let mut frame = gif::Frame::from_rgba(w as u16, h as u16, &mut pixels);
+            frame.delay = delay;
+            try!(encoder.write_frame(&frame));
+        }
+    }
+    Ok((buf, (w, h), hasher.result().to_hex()))
+}
+
+/// Difference hash, every bit says whether a pixel of a 9x8 grayscale thumbnail is brighter than its right neighbour
+///
+/// Unlike `content_hash` this barely changes when an image gets recompressed, see `hash_distance`.
+pub fn perceptual_hash(img: &DynamicImage) -> i64 {
+    let small = img.resize_exact(9, 8, image::FilterType::Triangle).to_luma();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y).data[0] > small.get_pixel(x + 1, y).data[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash as i64
+}
+
+/// Variance of the Laplacian of the image's brightness, close to 0 for blank or very blurry images
+///
+/// Computed on a copy scaled to fit 256x256, so scores of differently sized images can be compared.
+pub fn quality_score(img: &DynamicImage) -> f32 {
+    let small = fit_image(img, 256, 256, image::FilterType::Triangle).to_luma();
+    let (w, h) = small.dimensions();
+    if w < 3 || h < 3 {
+        return 0.0;
+    }
+
+    let px = |x: u32, y: u32| small.get_pixel(x, y).data[0] as f64;
+    let (mut sum, mut squares) = (0f64, 0f64);
+    for y in 1..h - 1 {
+        for x in 1..w - 1 {
+            let laplacian = px(x - 1, y) + px(x + 1, y) + px(x, y - 1) + px(x, y + 1) - 4.0 * px(x, y);
+            sum += laplacian;
+            squares += laplacian * laplacian;
+        }
+    }
+    let n = ((w - 2) * (h - 2)) as f64;
+    let mean = sum / n;
+    (squares / n - mean * mean) as f32
+}
+
+/// Average color of the image packed as `0xRRGGBB`, fully transparent pixels are left out
+pub fn accent_color(img: &DynamicImage) -> i32 {
+    let small = img.resize_exact(16, 16, image::FilterType::Triangle).to_rgba();
+    let (mut r, mut g, mut b, mut n) = (0u32, 0u32, 0u32, 0u32);
+    for p in small.pixels() {
+        if p.data[3] == 0 {
+            continue;
+        }
+        r += p.data[0] as u32;
+        g += p.data[1] as u32;
+        b += p.data[2] as u32;
+        n += 1;
+    }
+    if n == 0 {
+        return 0;
+    }
+    (((r / n) << 16) | ((g / n) << 8) | (b / n)) as i32
+}
+
+/// BlurHash (https://blurha.sh) of the image with 4x3 components, computed from a small thumbnail
+pub fn blurhash(img: &DynamicImage) -> String {
+    use std::f64::consts::PI;
+
+    static COMPONENTS : (u32, u32) = (4, 3);
+
+    fn to_linear(v: u8) -> f64 {
+        let v = v as f64 / 255.0;
+        if v <= 0.04045 { v / 12.92 } else { ((v + 0.055) / 1.055).powf(2.4) }
+    }
+
+    fn to_srgb(v: f64) -> u32 {
+        let v = v.max(0.0).min(1.0);
+        if v <= 0.0031308 {
+            (v * 12.92 * 255.0 + 0.5) as u32
+        } else {
+            ((1.055 * v.powf(1.0 / 2.4) - 0.055) * 255.0 + 0.5) as u32
+        }
+    }
+
+    fn sign_pow(v: f64, exp: f64) -> f64 {
+        if v < 0.0 { -(-v).powf(exp) } else { v.powf(exp) }
+    }
+
+    fn base83(out: &mut String, value: u32, length: u32) {
+        static CHARS : &'static [u8] =
+            b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+        for i in 1..length + 1 {
+            let digit = (value / 83u32.pow(length - i)) % 83;
+            out.push(CHARS[digit as usize] as char);
+        }
+    }
+
+    let small = fit_image(img, 32, 32, image::FilterType::Triangle).to_rgb();
+    let (w, h) = small.dimensions();
+
+    let mut factors = Vec::with_capacity((COMPONENTS.0 * COMPONENTS.1) as usize);
+    for j in 0..COMPONENTS.1 {
+        for i in 0..COMPONENTS.0 {
+            let norm = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut f = (0.0, 0.0, 0.0);
+            for y in 0..h {
+                for x in 0..w {
+                    let basis = (PI * i as f64 * x as f64 / w as f64).cos() * (PI * j as f64 * y as f64 / h as f64).cos();
+                    let p = small.get_pixel(x, y).data;
+                    f.0 += basis * to_linear(p[0]);
+                    f.1 += basis * to_linear(p[1]);
+                    f.2 += basis * to_linear(p[2]);
+                }
+            }
+            let scale = norm / (w * h) as f64;
+            factors.push((f.0 * scale, f.1 * scale, f.2 * scale));
+        }
+    }
+
+    let mut hash = String::new();
+    base83(&mut hash, (COMPONENTS.0 - 1) + (COMPONENTS.1 - 1) * 9, 1);
+
+    let (dc, ac) = factors.split_first().unwrap();
+    let actual_max = ac.iter().fold(0.0f64, |m, &(r, g, b)| m.max(r.abs()).max(g.abs()).max(b.abs()));
+    let quantised_max = (actual_max * 166.0 - 0.5).floor().max(0.0).min(82.0);
+    let max = (quantised_max + 1.0) / 166.0;
+    base83(&mut hash, quantised_max as u32, 1);
+
+    base83(&mut hash, (to_srgb(dc.0) << 16) + (to_srgb(dc.1) << 8) + to_srgb(dc.2), 4);
+
+    let quant = |v: f64| (sign_pow(v / max, 0.5) * 9.0 + 9.5).floor().max(0.0).min(18.0) as u32;
+    for &(r, g, b) in ac {
+        base83(&mut hash, quant(r) * 19 * 19 + quant(g) * 19 + quant(b), 2);
+    }
+    hash
+}
+
+/// Number of differing bits between two perceptual hashes
+pub fn hash_distance(a: i64, b: i64) -> u32 {
+    ((a ^ b) as u64).count_ones()
+}
+
+/// Where an image is stored
+///
+/// Values written by newer versions end up as `Unknown` rather than failing, so their rows can still be
+/// loaded and listed, just not read.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ImageType {
+    Local, Base64, S3, Unknown(i32),
+}
+
+impl ImageType {
+    pub fn from_i32(i: i32) -> Result<ImageType, error::FurryError> {
+        ImageType::try_from(i)
+    }
+
+    pub fn as_i32(&self) -> i32 {
+        match *self {
+            ImageType::Local => 0,
+            ImageType::Base64 => 1,
+            ImageType::S3 => 2,
+            ImageType::Unknown(i) => i,
+        }
+    }
+
+    /// The backend images of this type are read from and written to
+    pub fn store(&self) -> Result<&'static ImageStore, error::FurryError> {
+        match *self {
+            ImageType::Local => Ok(&LOCAL_STORE),
+            ImageType::Base64 => Ok(&BASE64_STORE),
+            ImageType::S3 => Ok(&S3_STORE),
+            ImageType::Unknown(i) => Err(error::FurryError::InvalidImageType(i)),
+        }
+    }
+}
+
+impl TryFrom<i32> for ImageType {
+    type Err = error::FurryError;
+
+    fn try_from(i: i32) -> Result<ImageType, error::FurryError> {
+        match i {
+            0 => Ok(ImageType::Local),
+            1 => Ok(ImageType::Base64),
+            2 => Ok(ImageType::S3),
+            _ => Ok(ImageType::Unknown(i)),
+        }
+    }
+}
+
+/// Formats written by newer versions end up as `Unknown`, those images can be served as they are but
+/// not resized
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ImageFormat {
+    PNG, GIF, JPEG, WEBP, ICO, BMP, AVIF, Unknown(i32),
+}
+
+impl ImageFormat {
+    pub fn from_i32(i: i32) -> Result<ImageFormat, error::FurryError> {
+        ImageFormat::try_from(i)
+    }
+
+    pub fn as_i32(&self) -> i32 {
+        match *self {
+            ImageFormat::PNG => 0,
+            ImageFormat::GIF => 1,
+            ImageFormat::JPEG => 2,
+            ImageFormat::WEBP => 3,
+            ImageFormat::ICO => 4,
+            ImageFormat::BMP => 5,
+            ImageFormat::AVIF => 6,
+            ImageFormat::Unknown(i) => i,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ImageFormat::PNG => "png",
+            ImageFormat::GIF => "gif",
+            ImageFormat::JPEG => "jpg",
+            ImageFormat::WEBP => "webp",
+            ImageFormat::ICO => "ico",
+            ImageFormat::BMP => "bmp",
+            ImageFormat::AVIF => "avif",
+            ImageFormat::Unknown(_) => "bin",
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match *self {
+            ImageFormat::PNG => "image/png",
+            ImageFormat::GIF => "image/gif",
+            ImageFormat::JPEG => "image/jpeg",
+            ImageFormat::WEBP => "image/webp",
+            ImageFormat::ICO => "image/x-icon",
+            ImageFormat::BMP => "image/bmp",
+            ImageFormat::AVIF => "image/avif",
+            ImageFormat::Unknown(_) => "application/octet-stream",
+        }
+    }
+
+    pub fn from_mime_type(mime: &str) -> Result<ImageFormat, error::FurryError> {
+        match &mime.trim().to_lowercase()[..] {
+            "image/png" => Ok(ImageFormat::PNG),
+            "image/gif" => Ok(ImageFormat::GIF),
+            "image/jpeg" | "image/jpg" => Ok(ImageFormat::JPEG),
+            "image/webp" => Ok(ImageFormat::WEBP),
+            "image/x-icon" | "image/vnd.microsoft.icon" => Ok(ImageFormat::ICO),
+            "image/bmp" => Ok(ImageFormat::BMP),
+            "image/avif" => Ok(ImageFormat::AVIF),
+            _ => Err(error::FurryError::UnknownImageFormat(mime.to_string())),
+        }
+    }
+
+    /// Whether transparency survives being stored in this format, it gets composited over a background otherwise
+    pub fn supports_alpha(&self) -> bool {
+        match *self {
+            ImageFormat::PNG | ImageFormat::GIF | ImageFormat::WEBP | ImageFormat::ICO | ImageFormat::AVIF => true,
+            ImageFormat::JPEG | ImageFormat::BMP | ImageFormat::Unknown(_) => false,
+        }
+    }
+
+    /// Whether the pinned `image` crate can write this format, it only ships a WebP decoder
+    ///
+    /// ICO and BMP have no encoder there either, we write those ourselves.
+    pub fn can_encode(&self) -> bool {
+        match *self {
+            ImageFormat::PNG | ImageFormat::GIF | ImageFormat::JPEG | ImageFormat::ICO | ImageFormat::BMP => true,
+            ImageFormat::WEBP | ImageFormat::AVIF | ImageFormat::Unknown(_) => false,
+        }
+    }
+
+    // Pretty shitty name
+    pub fn from_image_format(i: image::ImageFormat) -> Result<ImageFormat, error::FurryError> {
+        match i {
+            image::PNG  => Ok(ImageFormat::PNG),
+            image::GIF  => Ok(ImageFormat::GIF),
+            image::JPEG => Ok(ImageFormat::JPEG),
+            image::WEBP => Ok(ImageFormat::WEBP),
+            image::ICO  => Ok(ImageFormat::ICO),
+            image::BMP  => Ok(ImageFormat::BMP),
+            _ => Err(error::FurryError::UnsupportedImageFormat(i)),
+        }
+    }
+
+    /// The `image` crate's counterpart, which doesn't know AVIF at all
+    pub fn as_image_format(&self) -> Result<image::ImageFormat, error::FurryError> {
+        match *self {
+            ImageFormat::PNG => Ok(image::PNG),
+            ImageFormat::GIF => Ok(image::GIF),
+            ImageFormat::JPEG => Ok(image::JPEG),
+            ImageFormat::WEBP => Ok(image::WEBP),
+            ImageFormat::ICO => Ok(image::ICO),
+            ImageFormat::BMP => Ok(image::BMP),
+            ImageFormat::AVIF => Err(error::FurryError::UnknownImageFormat(self.as_str().to_string())),
+            ImageFormat::Unknown(i) => Err(error::FurryError::InvalidImageFormat(i)),
+        }
+    }
+}
+
+impl TryFrom<i32> for ImageFormat {
+    type Err = error::FurryError;
+
+    fn try_from(i: i32) -> Result<ImageFormat, error::FurryError> {
+        match i {
+            0 => Ok(ImageFormat::PNG),
+            1 => Ok(ImageFormat::GIF),
+            2 => Ok(ImageFormat::JPEG),
+            3 => Ok(ImageFormat::WEBP),
+            4 => Ok(ImageFormat::ICO),
+            5 => Ok(ImageFormat::BMP),
+            6 => Ok(ImageFormat::AVIF),
+            _ => Ok(ImageFormat::Unknown(i)),
+        }
+    }
+}
+
+impl fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A format uploads can come in, which besides the stored ones includes those only converted from
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum UploadFormat {
+    Stored(ImageFormat),
+    /// Stored as PNG, see `storage_format`
+    TIFF,
+}
+
+impl UploadFormat {
+    pub fn from_image_format(i: image::ImageFormat) -> Result<UploadFormat, error::FurryError> {
+        match i {
+            image::TIFF => Ok(UploadFormat::TIFF),
+            i => ImageFormat::from_image_format(i).map(UploadFormat::Stored),
+        }
+    }
+}
+
+impl fmt::Display for UploadFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UploadFormat::Stored(ref format) => f.write_str(format.as_str()),
+            UploadFormat::TIFF => f.write_str("tiff"),
+        }
+    }
+}
+
+impl FromStr for UploadFormat {
+    type Err = error::FurryError;
+
+    fn from_str(s: &str) -> Result<UploadFormat, error::FurryError> {
+        match &s.to_lowercase()[..] {
+            "tif" | "tiff" => Ok(UploadFormat::TIFF),
+            _ => s.parse().map(UploadFormat::Stored),
+        }
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = error::FurryError;
+
+    fn from_str(s: &str) -> Result<ImageFormat, error::FurryError> {
+        match &s.to_lowercase()[..] {
+            "png" => Ok(ImageFormat::PNG),
+            "gif" => Ok(ImageFormat::GIF),
+            "jpg" | "jpeg" => Ok(ImageFormat::JPEG),
+            "webp" => Ok(ImageFormat::WEBP),
+            "ico" => Ok(ImageFormat::ICO),
+            "bmp" => Ok(ImageFormat::BMP),
+            "avif" => Ok(ImageFormat::AVIF),
+            _ => Err(error::FurryError::UnknownImageFormat(s.to_string())),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ResizeMode {
+    /// Scale down to fit inside the box, keeping the aspect ratio
+    Fit,
+    /// Scale to cover the box and crop the overflow around the center
+    Fill,
+    /// Cut the box out of the center without scaling
+    Crop,
+    /// Scale to exactly the box, ignoring the aspect ratio
+    Stretch,
+    /// Fit inside the box and fill the rest of it with `background`, which formats without transparency
+    /// composite over the regular background
+    Pad { background: image::Rgba<u8> },
+}
+
+impl ResizeMode {
+    pub fn from_i32(i: i32) -> Result<ResizeMode, error::FurryError> {
+        ResizeMode::from_parts(i, 0)
+    }
+
+    /// The mode stored as `resize_mode` and `background`
+    pub fn from_parts(i: i32, background: i32) -> Result<ResizeMode, error::FurryError> {
+        match i {
+            0 => Ok(ResizeMode::Fit),
+            1 => Ok(ResizeMode::Fill),
+            2 => Ok(ResizeMode::Crop),
+            3 => Ok(ResizeMode::Stretch),
+            4 => {
+                let rgba = background as u32;
+                Ok(ResizeMode::Pad {
+                    background: image::Rgba { data: [(rgba >> 24) as u8, (rgba >> 16) as u8, (rgba >> 8) as u8, rgba as u8] },
+                })
+            }
+            _ => Err(error::FurryError::InvalidResizeMode(i)),
+        }
+    }
+
+    pub fn as_i32(&self) -> i32 {
+        match *self {
+            ResizeMode::Fit => 0,
+            ResizeMode::Fill => 1,
+            ResizeMode::Crop => 2,
+            ResizeMode::Stretch => 3,
+            ResizeMode::Pad { .. } => 4,
+        }
+    }
+
+    /// The padding color packed as RGBA, 0 for modes without one
+    pub fn background_i32(&self) -> i32 {
+        match *self {
+            ResizeMode::Pad { background } => {
+                let c = background.data;
+                ((c[0] as u32) << 24 | (c[1] as u32) << 16 | (c[2] as u32) << 8 | c[3] as u32) as i32
+            }
+            _ => 0,
+        }
+    }
+
+    pub fn apply(&self, img: &DynamicImage, width: u32, height: u32, filter: image::FilterType) -> DynamicImage {
+        resize_image(img, width, height, filter, *self)
+    }
+}
+
+/// Resizes `src` into a `width`x`height` box the way `mode` says, without touching the database or disk
+pub fn resize_image(src: &DynamicImage, width: u32, height: u32, filter: image::FilterType, mode: ResizeMode)
+    -> DynamicImage
+{
+    let (w, h) = src.dimensions();
+    match mode {
+        ResizeMode::Fit => fit_image(src, width, height, filter),
+        ResizeMode::Fill => {
+            let ratio = f64::max(width as f64 / w as f64, height as f64 / h as f64);
+            let (nw, nh) = ((w as f64 * ratio).ceil() as u32, (h as f64 * ratio).ceil() as u32);
+            let mut scaled = src.resize_exact(nw, nh, filter);
+            scaled.crop((nw - width) / 2, (nh - height) / 2, width, height)
+        }
+        ResizeMode::Crop => {
+            let (cw, ch) = (width.min(w), height.min(h));
+            src.clone().crop((w - cw) / 2, (h - ch) / 2, cw, ch)
+        }
+        ResizeMode::Stretch => src.resize_exact(width, height, filter),
+        ResizeMode::Pad { background } => {
+            use image::{ImageBuffer, Pixel, RgbaImage};
+
+            let fitted = fit_image(src, width, height, filter).to_rgba();
+            let (fw, fh) = fitted.dimensions();
+            let (dx, dy) = ((width - fw.min(width)) / 2, (height - fh.min(height)) / 2);
+            let mut canvas: RgbaImage = ImageBuffer::from_pixel(width, height, background);
+            for (x, y, p) in fitted.enumerate_pixels() {
+                if x + dx < width && y + dy < height {
+                    canvas.get_pixel_mut(x + dx, y + dy).blend(p);
+                }
+            }
+            DynamicImage::ImageRgba8(canvas)
+        }
+    }
+}
+
+/// Scales `src` to fit the box like `DynamicImage::resize`, which rounds very thin images down to nothing
+fn fit_image(src: &DynamicImage, width: u32, height: u32, filter: image::FilterType) -> DynamicImage {
+    let (w, h) = src.dimensions();
+    let (nw, nh) = fit_dimensions(w, h, width, height);
+    src.resize_exact(nw, nh, filter)
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Rotation {
+    R90, R180, R270,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FlipAxis {
+    Horizontal, Vertical,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Orientation {
+    Portrait, Landscape, Square,
+}
+
+/// What a derivative made by `Image::rotated` or `Image::flipped` did to its parent, `None` for resized ones
+#[repr(i32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Transform {
+    None, Rotate90, Rotate180, Rotate270, FlipHorizontal, FlipVertical,
+}
+
+impl Transform {
+    pub fn from_i32(i: i32) -> Result<Transform, error::FurryError> {
+        match i {
+            0 => Ok(Transform::None),
+            1 => Ok(Transform::Rotate90),
+            2 => Ok(Transform::Rotate180),
+            3 => Ok(Transform::Rotate270),
+            4 => Ok(Transform::FlipHorizontal),
+            5 => Ok(Transform::FlipVertical),
+            _ => Err(error::FurryError::InvalidTransform(i)),
+        }
+    }
+
+    pub fn apply(&self, img: &DynamicImage) -> DynamicImage {
+        match *self {
+            Transform::None => img.clone(),
+            Transform::Rotate90 => img.rotate90(),
+            Transform::Rotate180 => img.rotate180(),
+            Transform::Rotate270 => img.rotate270(),
+            Transform::FlipHorizontal => img.fliph(),
+            Transform::FlipVertical => img.flipv(),
+        }
+    }
+}
+
+impl From<Rotation> for Transform {
+    fn from(r: Rotation) -> Transform {
+        match r {
+            Rotation::R90 => Transform::Rotate90,
+            Rotation::R180 => Transform::Rotate180,
+            Rotation::R270 => Transform::Rotate270,
+        }
+    }
+}
+
+impl From<FlipAxis> for Transform {
+    fn from(f: FlipAxis) -> Transform {
+        match f {
+            FlipAxis::Horizontal => Transform::FlipHorizontal,
+            FlipAxis::Vertical => Transform::FlipVertical,
+        }
+    }
+}
+
+/// How hard zlib tries when compressing PNGs
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PngCompression {
+    Fast, Default, Best,
+}
+
+/// What every PNG scanline is run through before compressing, `Adaptive` tries all of them on each line
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PngFilter {
+    None, Sub, Up, Average, Paeth, Adaptive,
+}
+
+impl PngFilter {
+    /// The filter types as they are written to the scanlines
+    fn kinds(&self) -> &'static [u8] {
+        match *self {
+            PngFilter::None => &[0],
+            PngFilter::Sub => &[1],
+            PngFilter::Up => &[2],
+            PngFilter::Average => &[3],
+            PngFilter::Paeth => &[4],
+            PngFilter::Adaptive => &[0, 1, 2, 3, 4],
+        }
+    }
+}
+
+/// Format specific encoder settings, anything left unset is up to the encoder
+#[derive(Copy, Clone, Debug, Default)]
+pub struct EncodeOptions {
+    /// Between 1 and 100
+    pub jpeg_quality: Option<u8>,
+    /// Setting either of these makes PNGs go through our own encoder instead of the image crate's
+    pub png_compression: Option<PngCompression>,
+    pub png_filter: Option<PngFilter>,
+    /// What transparency gets composited over for formats that can't store it, `IMAGE_BACKGROUND` if unset
+    pub background: Option<(u8, u8, u8)>,
+}
+
+impl EncodeOptions {
+    pub fn new() -> EncodeOptions {
+        EncodeOptions::default()
+    }
+
+    /// What derivatives are encoded with unless asked otherwise, see `IMAGE_DERIVATIVE_QUALITY`
+    pub fn derivative() -> EncodeOptions {
+        EncodeOptions {
+            jpeg_quality: *DERIVATIVE_QUALITY,
+            png_compression: *DERIVATIVE_PNG_COMPRESSION,
+            png_filter: *DERIVATIVE_PNG_FILTER,
+            background: None,
+        }
+    }
+
+    pub fn background(&self) -> (u8, u8, u8) {
+        self.background.unwrap_or(*BACKGROUND)
+    }
+
+    pub fn with_background(mut self, rgb: (u8, u8, u8)) -> EncodeOptions {
+        self.background = Some(rgb);
+        self
+    }
+
+    pub fn with_jpeg_quality(mut self, quality: u8) -> EncodeOptions {
+        self.jpeg_quality = Some(quality);
+        self
+    }
+
+    pub fn with_png_compression(mut self, compression: PngCompression) -> EncodeOptions {
+        self.png_compression = Some(compression);
+        self
+    }
+
+    pub fn with_png_filter(mut self, filter: PngFilter) -> EncodeOptions {
+        self.png_filter = Some(filter);
+        self
+    }
+}
+
+/// How a derivative gets generated, only the mode is part of the cache key
+#[derive(Copy, Clone, Debug)]
+pub struct ResizeOptions {
+    pub mode: ResizeMode,
+    pub filter: image::FilterType,
+    /// Images are never enlarged unless this is set, a source smaller than the box is returned as is
+    pub allow_upscale: bool,
+    /// Snaps the requested box to the nearest `Image::nearest_preset` before looking for a derivative
+    pub snap_to_preset: bool,
+    pub encoding: EncodeOptions,
+}
+
+impl ResizeOptions {
+    pub fn new() -> ResizeOptions {
+        ResizeOptions {
+            mode: ResizeMode::Fit,
+            filter: image::FilterType::Lanczos3,
+            allow_upscale: false,
+            snap_to_preset: false,
+            encoding: EncodeOptions::derivative(),
+        }
+    }
+
+    pub fn with_encoding(mut self, encoding: EncodeOptions) -> ResizeOptions {
+        self.encoding = encoding;
+        self
+    }
+
+    pub fn with_snap_to_preset(mut self, snap: bool) -> ResizeOptions {
+        self.snap_to_preset = snap;
+        self
+    }
+
+    pub fn with_upscale(mut self, allow: bool) -> ResizeOptions {
+        self.allow_upscale = allow;
+        self
+    }
+
+    pub fn with_mode(mut self, mode: ResizeMode) -> ResizeOptions {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_filter(mut self, filter: image::FilterType) -> ResizeOptions {
+        self.filter = filter;
+        self
+    }
+
+    pub fn apply(&self, img: &DynamicImage, width: u32, height: u32) -> DynamicImage {
+        let (w, h) = img.dimensions();
+        if self.allow_upscale && self.mode == ResizeMode::Fit && w <= width && h <= height {
+            let (nw, nh) = fit_dimensions(w, h, width, height);
+            return img.resize_exact(nw, nh, self.filter);
+        }
+        resize_image(img, width, height, self.filter, self.mode)
+    }
+}
+
+fn nearest_preset_dim(size: i32) -> i32 {
+    nearest_of(&SIZE_PRESETS, size)
+}
+
+/// The one of `presets` closest to `size`, ties go to the larger one
+fn nearest_of(presets: &[i32], size: i32) -> i32 {
+    presets.iter().cloned()
+        .min_by_key(|&p| ((p - size).abs(), -p))
+        .unwrap_or(size)
+}
+
+/// Largest size with the aspect ratio of `width`x`height` that fits into the box, never less than 1px per side
+fn fit_dimensions(width: u32, height: u32, box_width: u32, box_height: u32) -> (u32, u32) {
+    let ratio = f64::min(box_width as f64 / width as f64, box_height as f64 / height as f64);
+    ((width as f64 * ratio).round().max(1.0) as u32, (height as f64 * ratio).round().max(1.0) as u32)
+}
+
+#[derive(Queryable, Identifiable, Clone)]
+pub struct Image {
+    pub id: i64,
+    pub created_at: diesel::data_types::PgTimestamp,
+    pub updated_at: diesel::data_types::PgTimestamp,
+    host_type: i32,
+    path: String,
+    /// The actual dimensions of the stored image
+    pub width: i32,
+    pub height: i32,
+    pub parent_id: Option<i64>,
+    /// The bounding box a derivative was requested with, used to find it again
+    pub wanted_height: Option<i32>,
+    pub wanted_width:  Option<i32>,
+    format: i32,
+    resize_mode: i32,
+    content_hash: Option<String>,
+    byte_size: i64,
+    /// Set when the image got taken down, the row and its file stay around so it can be restored
+    pub deleted_at: Option<diesel::data_types::PgTimestamp>,
+    /// The user that uploaded the image, derivatives share it with their parent
+    pub owner_id: Option<i64>,
+    phash: Option<i64>,
+    accent_color: Option<i32>,
+    blurhash: Option<String>,
+    has_alpha: bool,
+    transform: i32,
+    /// When the derivative was last served, only kept up to date to within `ACCESS_TOUCH_INTERVAL_SECS`
+    pub last_accessed_at: diesel::data_types::PgTimestamp,
+    background: i32,
+    /// When the photo was taken according to its EXIF data, only JPEG uploads carry one
+    pub captured_at: Option<diesel::data_types::PgTimestamp>,
+    quality_score: Option<f32>,
+    /// SHA-256 of the stored bytes, unlike `content_hash` it changes whenever the image is encoded differently
+    file_hash: Option<String>,
+}
+
+impl Image {
+    /// The row `new` would become when inserted with `id`, without touching the database
+    ///
+    /// All timestamps are set to now, meant for code that works on images without storing them, like tests.
+    pub fn from_parts(id: i64, new: NewImage) -> Image {
+        let now = helper::utc_to_pg_timestamp(&Utc::now());
+        Image {
+            id: id,
+            created_at: now,
+            updated_at: now,
+            host_type: new.host_type,
+            path: new.path,
+            width: new.width,
+            height: new.height,
+            parent_id: new.parent_id,
+            wanted_height: new.wanted_height,
+            wanted_width: new.wanted_width,
+            format: new.format,
+            resize_mode: new.resize_mode,
+            content_hash: new.content_hash,
+            byte_size: new.byte_size,
+            deleted_at: None,
+            owner_id: new.owner_id,
+            phash: new.phash,
+            accent_color: new.accent_color,
+            blurhash: new.blurhash,
+            has_alpha: new.has_alpha,
+            transform: new.transform,
+            last_accessed_at: now,
+            background: new.background,
+            captured_at: new.captured_at,
+            quality_score: new.quality_score,
+            file_hash: new.file_hash,
+        }
+    }
+
+    pub fn create_from(new: NewImage) -> Result<i64, error::FurryError> {
+        use diesel;
+        use diesel::prelude::*;
+        use models::schema::images::dsl::*;
+        let conn = try!(database::conn());
+        diesel::insert(&new).into(images)
+            .returning(id).get_result(&*conn).map_err(|e| e.into())
+    }
+
+    /// Inserts the image, its file gets removed again if that fails
+    fn insert_or_discard(new: NewImage) -> Result<Image, error::FurryError> {
+        use diesel;
+        use diesel::prelude::*;
+        use models::schema::images::dsl::*;
+
+        let conn = try!(database::conn());
+        match diesel::insert(&new).into(images).get_result::<Image>(&*conn) {
+            Ok(i) => Ok(i),
+            Err(e) => {
+                try!(new.remove_file());
+                Err(e.into())
+            }
+        }
+    }
+
+    /// The original image with the same pixels as `bytes` if there is one, otherwise they get stored as a new
+    /// image, the flag tells which of both happened
+    pub fn find_or_create_from_bytes(bytes: &[u8], opts: EncodeOptions) -> Result<(Image, bool), error::FurryError> {
+        let fmt = try!(guess_allowed_format(bytes));
+        let img = orient(try!(image::load_from_memory_with_format(bytes, fmt)), bytes);
+        if let Some(i) = try!(find_by_hash(&content_hash(&img))) {
+            return Ok((i, false));
+        }
+
+        let mut new = try!(NewImage::create_from_dynamic_image_with_encoding(&img, "upload", fmt, opts));
+        new.captured_at = captured_at(bytes);
+        Image::insert_or_discard(new).map(|i| (i, true))
+    }
+
+    /// Inserts all images in a single statement, either every one of them gets inserted or none
+    pub fn create_from_many(news: Vec<NewImage>) -> Result<Vec<i64>, error::FurryError> {
+        use diesel;
+        use diesel::prelude::*;
+        use models::schema::images::dsl::*;
+
+        if news.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let conn = try!(database::conn());
+        conn.transaction(|| {
+            diesel::insert(&news[..]).into(images)
+                .returning(id).get_results::<i64>(&*conn)
+        }).map_err(|e| e.into())
+    }
+
+    /// All derivatives generated from this image, smallest first
+    pub fn children(&self) -> Result<Vec<Image>, error::FurryError> {
+        use diesel::prelude::*;
+        use models::schema::images::dsl::*;
+
+        let conn = try!(database::conn());
+        images.filter(parent_id.eq(self.id)).order(width.asc())
+            .get_results::<Image>(&*conn).map_err(|e| e.into())
+    }
+
+    /// Number of derivatives that are not soft deleted
+    pub fn derivative_count(&self) -> Result<i64, error::FurryError> {
+        use diesel::prelude::*;
+        use models::schema::images::dsl::*;
+
+        let conn = try!(database::conn());
+        images.filter(parent_id.eq(self.id)).filter(deleted_at.is_null()).count()
+            .get_result::<i64>(&*conn).map_err(|e| e.into())
+    }
+
+    /// Fails if `adding` more derivatives would go over what `IMAGE_MAX_DERIVATIVES` allows
+    fn check_derivative_limit(&self, adding: i64) -> Result<(), error::FurryError> {
+        let conn = try!(database::conn());
+        if try!(exceeds_derivative_limit(&*conn, self.id, adding)) {
+            return Err(error::FurryError::DerivativeLimit { id: self.id, max: MAX_DERIVATIVES.unwrap_or(0) });
+        }
+        Ok(())
+    }
+
+    /// Removes the image and its file, forcing it takes its derivatives along
+    ///
+    /// Derivatives are never left behind on their own, without a parent they would pass for originals.
+    pub fn delete(&self, force: bool) -> Result<(), error::FurryError> {
+        use diesel;
+        use diesel::prelude::*;
+        use models::schema::images::dsl::*;
+
+        if force {
+            return self.delete_with_children().map(|_| ());
+        }
+
+        let conn = try!(database::conn());
+        try!(conn.transaction::<_, error::FurryError, _>(|| {
+            let children = try!(images.filter(parent_id.eq(self.id)).count().get_result::<i64>(&*conn));
+            if children > 0 {
+                return Err(error::FurryError::HasDerivatives(self.id));
+            }
+            try!(diesel::delete(images.filter(id.eq(self.id))).execute(&*conn));
+            Ok(())
+        }));
+
+        self.remove_file()
+    }
+
+    /// Removes the image together with all of its derivatives, returns how many images were deleted
+    ///
+    /// Derivatives of derivatives, like a resized copy of a rotated one, go as well.
+    pub fn delete_with_children(&self) -> Result<usize, error::FurryError> {
+        use diesel;
+        use diesel::prelude::*;
+        use models::schema::images::dsl::*;
+
+        let conn = try!(database::conn());
+        let children = try!(conn.transaction::<_, error::FurryError, _>(|| {
+            let mut children = Vec::new();
+            let mut parents = vec![self.id];
+            while !parents.is_empty() {
+                let found = try!(images.filter(parent_id.eq_any(parents)).get_results::<Image>(&*conn));
+                parents = found.iter().map(|i| i.id).collect();
+                children.extend(found);
+            }
+
+            // A single statement, the foreign keys only get checked once all of the rows are gone
+            let mut ids = children.iter().map(|i| i.id).collect::<Vec<_>>();
+            ids.push(self.id);
+            try!(diesel::delete(images.filter(id.eq_any(ids))).execute(&*conn));
+            Ok(children)
+        }));
+
+        // Files can only go once the rows are gone for good
+        for child in &children {
+            try!(child.remove_file());
+        }
+        try!(self.remove_file());
+        Ok(children.len() + 1)
+    }
+
+    /// Hides the image and its derivatives from every lookup without touching the files
+    pub fn soft_delete(&self) -> Result<(), error::FurryError> {
+        self.set_deleted_at(Some(helper::utc_to_pg_timestamp(&Utc::now())))
+    }
+
+    /// Undoes `soft_delete`
+    pub fn restore(&self) -> Result<(), error::FurryError> {
+        self.set_deleted_at(None)
+    }
+
+    fn set_deleted_at(&self, at: Option<diesel::data_types::PgTimestamp>) -> Result<(), error::FurryError> {
+        use diesel;
+        use diesel::prelude::*;
+        use models::schema::images::dsl::*;
+
+        let conn = try!(database::conn());
+        try!(diesel::update(images.filter(id.eq(self.id).or(parent_id.eq(self.id))))
+             .set(deleted_at.eq(at)).execute(&*conn));
+        Ok(())
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
+    fn remove_file(&self) -> Result<(), error::FurryError> {
+        DECODE_CACHE.lock().unwrap().remove(self.id);
+        remove_stored_file(self.host_type, &self.path)
+    }
+
+    /// The backend the image is stored in
+    fn store(&self) -> Result<&'static ImageStore, error::FurryError> {
+        try!(ImageType::from_i32(self.host_type)).store()
+    }
+
+    pub fn get_path(&self) -> Result<String, error::FurryError> {
+        try!(self.store()).url(self)
+    }
+
+    pub fn get_with_size(&self, width: i32, height: i32) -> Result<Image, error::FurryError> {
+        self.get_with_size_mode(width, height, ResizeMode::Fit)
+    }
+
+    /// The smallest already stored fit of this image that is at least as large as `get_with_size` would make it,
+    /// only generates a new derivative if none is
+    pub fn best_fit(&self, width: i32, height: i32) -> Result<Image, error::FurryError> {
+        try!(check_requested_size(width, height));
+        if self.is_deleted() {
+            return Err(error::FurryError::NotFound);
+        }
+        if self.width <= width && self.height <= height {
+            return Ok(self.clone());
+        }
+
+        // Fits share the original's aspect ratio, so covering one side is enough to not lose to rounding
+        let (tw, th) = fit_dimensions(self.width as u32, self.height as u32, width as u32, height as u32);
+        let best = try!(self.children()).into_iter()
+            .filter(|i| !i.is_deleted() && i.transform == Transform::None as i32 && i.format == self.format)
+            .filter(|i| i.get_resize_mode().ok() == Some(ResizeMode::Fit))
+            .find(|i| i.width as u32 >= tw || i.height as u32 >= th);
+
+        match best {
+            Some(i) => Ok(i),
+            None => self.get_with_size(width, height),
+        }
+    }
+
+    pub fn get_with_size_mode(&self, width: i32, height: i32, mode: ResizeMode) -> Result<Image, error::FurryError> {
+        self.get_with_size_options(width, height, ResizeOptions::new().with_mode(mode))
+    }
+
+    /// Fits the image to `width`, the height follows from the aspect ratio so equal widths share a derivative
+    pub fn get_with_width(&self, width: i32) -> Result<Image, error::FurryError> {
+        try!(check_requested_size(width, 1));
+        self.get_with_size(width, self.height_for_width(width))
+    }
+
+    fn height_for_width(&self, width: i32) -> i32 {
+        (width as f64 * self.height as f64 / self.width as f64).round().max(1.0) as i32
+    }
+
+    /// Like `get_with_width`, but for the height
+    pub fn get_with_height(&self, height: i32) -> Result<Image, error::FurryError> {
+        try!(check_requested_size(1, height));
+        let width = (height as f64 * self.width as f64 / self.height as f64).round().max(1.0) as i32;
+        self.get_with_size(width, height)
+    }
+
+    /// A `size`x`size` square cut from the center, smaller sources get scaled up so the avatar is always that size
+    pub fn avatar(&self, size: i32) -> Result<Image, error::FurryError> {
+        self.get_with_size_options(size, size, ResizeOptions::new().with_mode(ResizeMode::Fill).with_upscale(true))
+    }
+
+    /// Snaps each side of the requested box to the nearest of the configured `IMAGE_SIZE_PRESETS`
+    pub fn nearest_preset(&self, width: i32, height: i32) -> (i32, i32) {
+        (nearest_preset_dim(width), nearest_preset_dim(height))
+    }
+
+    pub fn get_with_size_options(&self, width: i32, height: i32, opts: ResizeOptions) -> Result<Image, error::FurryError> {
+        self.ensure_size_options(width, height, opts).map(|(i, _)| i)
+    }
+
+    /// Like `get_with_size`, but also tells whether the derivative had to be created
+    pub fn ensure_size(&self, width: i32, height: i32) -> Result<(Image, bool), error::FurryError> {
+        self.ensure_size_options(width, height, ResizeOptions::new())
+    }
+
+    pub fn ensure_size_options(&self, width: i32, height: i32, opts: ResizeOptions)
+        -> Result<(Image, bool), error::FurryError>
+    {
+        try!(check_requested_size(width, height));
+        let (width, height) = if opts.snap_to_preset { self.nearest_preset(width, height) } else { (width, height) };
+        // Its derivatives are hidden as well, a new one would collide with them
+        if self.is_deleted() {
+            return Err(error::FurryError::NotFound);
+        }
+
+        // Opaque blobs can't be resized, serving them whole beats not serving them
+        if let ImageFormat::Unknown(_) = try!(self.get_format()) {
+            return Ok((self.clone(), false));
+        }
+
+        let pad = match opts.mode {
+            ResizeMode::Pad { .. } => self.width != width || self.height != height,
+            _ => false,
+        };
+        if self.width > width || self.height > height || opts.allow_upscale || pad {
+            match find_from_image_with_format(self.id, width, height, opts.mode, try!(self.get_format())) {
+                Ok(Some(i)) => Ok((i, false)),
+                Ok(None) => {
+                    try!(self.check_derivative_limit(1));
+                    let new_image = try!(NewImage::create_from_image_with_options(self, width, height, opts));
+                    Image::create_derivative(new_image, self.id, width, height, opts.mode)
+                }
+                Err(e) => Err(e),
+            }
+        } else {
+            Ok((self.clone(), false))
+        }
+    }
+
+    /// Every width in every format for a `<picture>` element, ordered by width and then by format
+    ///
+    /// Each width is fit like `get_with_width` but never past the original's. Asking for a format without an
+    /// encoder fails the whole set with `EncoderUnavailable` before anything gets stored, so check `can_encode`
+    /// first. Missing derivatives are resized from a single decode and stored like any other.
+    pub fn responsive_set(&self, widths: &[i32], formats: &[ImageFormat]) -> Result<Vec<Image>, error::FurryError> {
+        use std::cmp;
+
+        if self.is_deleted() {
+            return Err(error::FurryError::NotFound);
+        }
+        let own = try!(self.resizable_format());
+        if let Some(f) = formats.iter().find(|f| !f.can_encode()) {
+            return Err(error::FurryError::EncoderUnavailable(f.as_str()));
+        }
+
+        let opts = ResizeOptions::new();
+        let mut source = None;
+        let mut set = Vec::with_capacity(widths.len() * formats.len());
+        for &width in widths {
+            try!(check_requested_size(width, 1));
+            let width = cmp::min(width, self.width);
+            let height = self.height_for_width(width);
+            let mut resized = None;
+
+            for &fmt in formats {
+                if width == self.width && fmt == own {
+                    set.push(self.clone());
+                    continue;
+                }
+                if let Some(i) = try!(find_from_image_with_format(self.id, width, height, opts.mode, fmt)) {
+                    set.push(i);
+                    continue;
+                }
+
+                try!(self.check_derivative_limit(1));
+                if source.is_none() {
+                    try!(check_dimensions(self.width as u32, self.height as u32));
+                    source = Some(try!(self.decode_cached()));
+                }
+                if resized.is_none() {
+                    resized = source.as_ref().map(|s| opts.apply(s, width as u32, height as u32));
+                }
+                let new = try!(NewImage::create_from_dynamic_image_in(resized.as_ref().unwrap(),
+                                                                      &format!("orig_{}", self.id)[..], fmt,
+                                                                      *INLINE_MAX_DIM, opts.encoding, Store::Derivatives));
+                let new = new.with_parent(self.id).with_owner_of(self).with_wanted_size(width, height)
+                             .with_resize_mode(opts.mode);
+                set.push(try!(Image::create_derivative(new, self.id, width, height, opts.mode)).0);
+            }
+        }
+        Ok(set)
+    }
+
+    /// The encoded image, wherever it is stored
+    pub fn bytes(&self) -> Result<Vec<u8>, error::FurryError> {
+        try!(self.store()).read(self)
+    }
+
+    /// Size of the encoded image in bytes without reading it, local files are stat'ed
+    pub fn content_length(&self) -> Result<u64, error::FurryError> {
+        try!(self.store()).content_length(self)
+    }
+
+    /// A reader over the encoded image, local files are streamed instead of read at once
+    pub fn open_reader(&self) -> Result<Box<Read>, error::FurryError> {
+        try!(self.store()).open(self)
+    }
+
+    /// Reads and decodes the stored image
+    pub fn decode(&self) -> Result<DynamicImage, error::FurryError> {
+        let bytes = try!(self.bytes());
+        // The bytes know their format better than the column, which is only used if they can't be sniffed,
+        // older inline images were always encoded as PNG whatever their format says
+        let fmt = match image::guess_format(&bytes[..]) {
+            Ok(fmt) => fmt,
+            Err(_) => try!(try!(self.get_format()).as_image_format()),
+        };
+        let image = try!(image::load_from_memory_with_format(&bytes[..], fmt));
+        Ok(orient(image, &bytes[..]))
+    }
+
+    /// A still thumbnail of the first frame, stored as PNG next to any animated derivative of the same size
+    ///
+    /// Anything but a GIF just gets its regular derivative.
+    pub fn poster_frame(&self, width: i32, height: i32) -> Result<Image, error::FurryError> {
+        match try!(self.get_format()) {
+            ImageFormat::GIF => {}
+            _ => return self.get_with_size(width, height),
+        }
+
+        try!(check_requested_size(width, height));
+        // Its derivatives are hidden as well, a new one would collide with them
+        if self.is_deleted() {
+            return Err(error::FurryError::NotFound);
+        }
+        // Like any other derivative the poster is never scaled up, a GIF that already fits gets one of its own size
+        let (width, height) = if self.width <= width && self.height <= height {
+            (self.width, self.height)
+        } else {
+            (width, height)
+        };
+
+        let opts = ResizeOptions::new();
+        if let Some(i) = try!(find_from_image_with_format(self.id, width, height, opts.mode, ImageFormat::PNG)) {
+            return Ok(i);
+        }
+
+        try!(self.check_derivative_limit(1));
+        try!(check_dimensions(self.width as u32, self.height as u32));
+        // The image crate only ever decodes the first frame, which is exactly the one we want
+        let resized = opts.apply(&try!(self.decode()), width as u32, height as u32);
+        let new = try!(NewImage::create_from_dynamic_image_in(&resized, &format!("poster_{}", self.id)[..], ImageFormat::PNG,
+                                                              *INLINE_MAX_DIM, EncodeOptions::new(), Store::Derivatives));
+        Image::create_derivative(new.with_parent(self.id).with_owner_of(self).with_wanted_size(width, height)
+                                    .with_resize_mode(opts.mode),
+                                 self.id, width, height, opts.mode).map(|(i, _)| i)
+    }
+
+    /// Encodes what `get_with_size` would produce without storing a file or inserting a row
+    pub fn preview_resize(&self, width: i32, height: i32) -> Result<Vec<u8>, error::FurryError> {
+        try!(check_requested_size(width, height));
+        // Images that already fit aren't scaled up, `get_with_size` hands those out as they are
+        if self.width <= width && self.height <= height {
+            return self.bytes();
+        }
+        try!(check_dimensions(self.width as u32, self.height as u32));
+
+        let opts = ResizeOptions::new();
+        let format = try!(self.resizable_format());
+        if let ImageFormat::GIF = format {
+            if try!(self.is_animated()) {
+                let (buf, _, _) = try!(resize_animated_gif(&try!(self.bytes()), width as u32, height as u32, opts));
+                return Ok(buf);
+            }
+        }
+
+        let resized = opts.apply(&try!(self.decode_cached()), width as u32, height as u32);
+        encode_for_storage(&resized, format, opts.encoding).map(|(_, buf)| buf)
+    }
+
+    /// Like `decode` but served from the cache of recently decoded images if possible
+    pub fn decode_cached(&self) -> Result<Arc<DynamicImage>, error::FurryError> {
+        if let Some(img) = DECODE_CACHE.lock().unwrap().get(self.id) {
+            return Ok(img);
+        }
+        // Decoding happens without holding the lock, at worst two requests decode the same image
+        let img = Arc::new(try!(self.decode()));
+        DECODE_CACHE.lock().unwrap().insert(self.id, img.clone());
+        Ok(img)
+    }
+
+    /// A smaller version of this image that is still at least twice the size of the `width`x`height` box,
+    /// decoded without ever holding all of the original's pixels
+    ///
+    /// Only worth it for sources much larger than the box and only non-interlaced PNGs can be decoded like that,
+    /// `None` means a regular decode is needed. An already cached decode is always preferred.
+    fn decode_downscaled(&self, width: u32, height: u32) -> Result<Option<DynamicImage>, error::FurryError> {
+        let factor = u32::min(self.width as u32 / width, self.height as u32 / height) / 2;
+        if factor < STREAMING_MIN_FACTOR || try!(self.get_format()) != ImageFormat::PNG
+            || DECODE_CACHE.lock().unwrap().contains(self.id)
+        {
+            return Ok(None);
+        }
+        let bytes = try!(self.bytes());
+        Ok(decode_png_downscaled(&bytes[..], factor))
+    }
+
+    /// Whether the stored width and height match the ones the file actually decodes to
+    pub fn verify_dimensions(&self) -> Result<bool, error::FurryError> {
+        let dims = try!(self.decode()).dimensions();
+        Ok(dims == (self.width as u32, self.height as u32))
+    }
+
+    /// Whether this is a GIF with more than one frame
+    pub fn is_animated(&self) -> Result<bool, error::FurryError> {
+        match try!(self.get_format()) {
+            ImageFormat::GIF => is_animated_gif(&try!(self.bytes())),
+            _ => Ok(false),
+        }
+    }
+
+    /// Generates all the given sizes at once, decoding this image only a single time
+    ///
+    /// The returned images are in the same order as `sizes`, which should not contain duplicates.
+    pub fn generate_sizes(&self, sizes: &[(i32, i32)]) -> Result<Vec<Image>, error::FurryError> {
+        self.generate_sizes_with(sizes, false)
+    }
+
+    /// Like `generate_sizes` but resizes to all sizes in parallel, encoding and inserting still happen one by one
+    pub fn generate_sizes_parallel(&self, sizes: &[(i32, i32)]) -> Result<Vec<Image>, error::FurryError> {
+        self.generate_sizes_with(sizes, true)
+    }
+
+    fn generate_sizes_with(&self, sizes: &[(i32, i32)], parallel: bool) -> Result<Vec<Image>, error::FurryError> {
+        use diesel;
+        use diesel::prelude::*;
+        use models::schema::images::dsl::*;
+
+        let opts = ResizeOptions::new();
+        let fmt = try!(self.get_format());
+        let mut found = Vec::with_capacity(sizes.len());
+        let mut missing = Vec::new();
+
+        for (idx, &(w, h)) in sizes.iter().enumerate() {
+            try!(check_requested_size(w, h));
+            if self.width <= w && self.height <= h {
+                found.push((idx, self.clone()));
+            } else if let Some(i) = try!(find_from_image_with_format(self.id, w, h, opts.mode, fmt)) {
+                found.push((idx, i));
+            } else {
+                missing.push((idx, w, h));
+            }
+        }
+
+        if !missing.is_empty() {
+            try!(self.check_derivative_limit(missing.len() as i64));
+            try!(check_dimensions(self.width as u32, self.height as u32));
+            // Animated GIFs can't share a single decoded frame, every size goes through its own resize
+            let source = if try!(self.is_animated()) { None } else { Some(try!(self.decode_cached())) };
+
+            let resized = match source {
+                Some(ref source) if parallel => {
+                    use rayon::prelude::*;
+                    Some(missing.par_iter()
+                         .map(|&(_, w, h)| opts.apply(source, w as u32, h as u32))
+                         .collect::<Vec<_>>())
+                }
+                _ => None,
+            };
+
+            let mut news = Vec::with_capacity(missing.len());
+            for (n, &(_, w, h)) in missing.iter().enumerate() {
+                let new = match (&source, &resized) {
+                    (_, &Some(ref r)) => NewImage::create_from_resized(self, &r[n], w, h, opts),
+                    (&Some(ref source), &None) => NewImage::create_from_decoded(self, source, w, h, opts),
+                    (&None, &None) => NewImage::create_from_animated(self, w, h, opts),
+                };
+                match new {
+                    Ok(n) => news.push(n),
+                    Err(e) => {
+                        for n in &news {
+                            let _ = n.remove_file();
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+
+            let conn = try!(database::conn());
+            let inserted = conn.transaction(|| {
+                diesel::insert(&news[..]).into(images).get_results::<Image>(&*conn)
+            });
+
+            match inserted {
+                Ok(rows) => found.extend(missing.iter().map(|&(idx, _, _)| idx).zip(rows)),
+                Err(e) => {
+                    for n in &news {
+                        let _ = n.remove_file();
+                    }
+                    return Err(e.into());
+                }
+            }
+        }
+
+        found.sort_by_key(|&(idx, _)| idx);
+        Ok(found.into_iter().map(|(_, i)| i).collect())
+    }
+
+    /// A copy rotated by the given angle, stored as derivative and reused from then on
+    pub fn rotated(&self, rotation: Rotation) -> Result<Image, error::FurryError> {
+        self.transformed(rotation.into())
+    }
+
+    /// A mirrored copy, stored as derivative and reused from then on
+    pub fn flipped(&self, axis: FlipAxis) -> Result<Image, error::FurryError> {
+        self.transformed(axis.into())
+    }
+
+    fn transformed(&self, transform: Transform) -> Result<Image, error::FurryError> {
+        if self.is_deleted() {
+            return Err(error::FurryError::NotFound);
+        }
+
+        let fmt = try!(self.resizable_format());
+        if let Some(i) = try!(find_transformed(self.id, transform, fmt)) {
+            return Ok(i);
+        }
+
+        try!(check_dimensions(self.width as u32, self.height as u32));
+        let result = transform.apply(&try!(self.decode_cached()));
+        let (w, h) = result.dimensions();
+        let new = try!(NewImage::create_from_dynamic_image_in(&result, &format!("orig_{}", self.id)[..], fmt,
+                                                              *INLINE_MAX_DIM, EncodeOptions::new(), Store::Derivatives));
+        let mut new = new.with_parent(self.id).with_owner_of(self).with_wanted_size(w as i32, h as i32);
+        new.transform = transform as i32;
+
+        let uid = self.id;
+        Image::insert_unless_exists(new, |conn| query_transformed(conn, uid, transform, fmt)).map(|(i, _)| i)
+    }
+
+    /// A copy of the image encoded as `to`, stored as an image of its own with the same owner
+    ///
+    /// Animated GIFs only keep their first frame.
+    pub fn transcode(&self, to: ImageFormat, opts: EncodeOptions) -> Result<Image, error::FurryError> {
+        Image::insert_or_discard(try!(self.transcoded(to, opts)))
+    }
+
+    /// Like `transcode` but swaps the file of this image for the transcoded one, keeping id and derivatives
+    ///
+    /// Derivatives in the old format stay around until pruned, new ones get made in the new format.
+    pub fn transcode_in_place(&self, to: ImageFormat, opts: EncodeOptions) -> Result<Image, error::FurryError> {
+        self.replace_stored(try!(self.transcoded(to, opts)))
+    }
+
+    /// Generates this derivative again from its parent and swaps its file for the new one, keeping the row
+    ///
+    /// Meant for derivatives whose file got corrupted or lost, originals have nothing to be regenerated from.
+    pub fn regenerate(&self) -> Result<(), error::FurryError> {
+        self.regenerate_with(ResizeOptions::new())
+    }
+
+    /// Like `regenerate` but resizes with the filter and encoding of `opts`, its mode is ignored in favour of
+    /// the one this derivative was made with
+    pub fn regenerate_with(&self, opts: ResizeOptions) -> Result<(), error::FurryError> {
+        let parent = match self.parent_id {
+            Some(pid) => try!(try!(find_include_deleted(pid)).ok_or(error::FurryError::NotFound)),
+            None => return Err(error::FurryError::NotADerivative(self.id)),
+        };
+        self.regenerate_from(&parent, opts)
+    }
+
+    fn regenerate_from(&self, parent: &Image, opts: ResizeOptions) -> Result<(), error::FurryError> {
+        let fmt = try!(self.get_format());
+        try!(parent.resizable_format());
+        try!(check_dimensions(parent.width as u32, parent.height as u32));
+
+        let suffix = format!("orig_{}", parent.id);
+        let new = match try!(self.get_transform()) {
+            Transform::None => {
+                let opts = opts.with_mode(try!(self.get_resize_mode()));
+                let w = self.wanted_width.unwrap_or(self.width);
+                let h = self.wanted_height.unwrap_or(self.height);
+                if fmt == ImageFormat::GIF && try!(parent.is_animated()) {
+                    let (buf, dims, hash) = try!(resize_animated_gif(&try!(parent.bytes()), w as u32, h as u32, opts));
+                    let mut new = try!(NewImage::store_encoded(buf, dims, fmt, &suffix, *INLINE_MAX_DIM, hash,
+                                                               Store::Derivatives));
+                    new.has_alpha = parent.has_alpha;
+                    new
+                } else {
+                    let resized = opts.apply(&try!(parent.decode_cached()), w as u32, h as u32);
+                    try!(NewImage::create_from_dynamic_image_in(&resized, &suffix, fmt, *INLINE_MAX_DIM,
+                                                                opts.encoding, Store::Derivatives))
+                }
+            }
+            transform => {
+                let result = transform.apply(&try!(parent.decode_cached()));
+                try!(NewImage::create_from_dynamic_image_in(&result, &suffix, fmt, *INLINE_MAX_DIM,
+                                                            EncodeOptions::new(), Store::Derivatives))
+            }
+        };
+        self.replace_stored(new).map(|_| ())
+    }
+
+    /// Points this row at the file of `new` and removes the old one, `new`'s file is removed if that fails
+    fn replace_stored(&self, new: NewImage) -> Result<Image, error::FurryError> {
+        use diesel;
+        use diesel::prelude::*;
+        use models::schema::images::dsl::*;
+
+        let conn = try!(database::conn());
+        let res = diesel::update(images.filter(id.eq(self.id)))
+            .set((host_type.eq(new.host_type),
+                  path.eq(&new.path),
+                  format.eq(new.format),
+                  width.eq(new.width),
+                  height.eq(new.height),
+                  content_hash.eq(&new.content_hash),
+                  byte_size.eq(new.byte_size),
+                  phash.eq(new.phash),
+                  accent_color.eq(new.accent_color),
+                  blurhash.eq(&new.blurhash),
+                  has_alpha.eq(new.has_alpha),
+                  quality_score.eq(new.quality_score),
+                  file_hash.eq(&new.file_hash)))
+            .get_result::<Image>(&*conn);
+
+        match res {
+            Ok(i) => {
+                try!(self.remove_file());
+                Ok(i)
+            }
+            Err(e) => {
+                try!(new.remove_file());
+                Err(e.into())
+            }
+        }
+    }
+
+    fn transcoded(&self, to: ImageFormat, opts: EncodeOptions) -> Result<NewImage, error::FurryError> {
+        try!(self.resizable_format());
+        try!(check_dimensions(self.width as u32, self.height as u32));
+        let source = try!(self.decode_cached());
+        let new = try!(NewImage::create_from_dynamic_image_in(&source, &format!("transcode_{}", self.id)[..], to,
+                                                              *INLINE_MAX_DIM, opts, Store::Uploads));
+        Ok(new.with_owner_of(self))
+    }
+
+    pub fn get_transform(&self) -> Result<Transform, error::FurryError> {
+        Transform::from_i32(self.transform)
+    }
+
+    fn create_derivative(new: NewImage, uid: i64, w: i32, h: i32, mode: ResizeMode)
+        -> Result<(Image, bool), error::FurryError>
+    {
+        let fmt = try!(ImageFormat::from_i32(new.format));
+        Image::insert_unless_exists(new, |conn| query_from_image(conn, uid, w, h, mode, Some(fmt)))
+    }
+
+    /// Inserts a derivative unless `lookup` finds an identical one inserted in the meantime, in which case that
+    /// one is returned and the freshly written file discarded, the flag tells which of both happened
+    fn insert_unless_exists<F>(new: NewImage, lookup: F) -> Result<(Image, bool), error::FurryError>
+        where F: Fn(&PgConnection) -> diesel::QueryResult<Option<Image>>
+    {
+        use diesel;
+        use diesel::prelude::*;
+        use diesel::result::{DatabaseErrorKind, Error, TransactionError};
+        use models::schema::images::dsl::*;
+
+        let conn = try!(database::conn());
+        let res = conn.transaction(|| {
+            if let Some(i) = try!(lookup(&*conn)) {
+                return Ok(Some((i, false)));
+            }
+            if let Some(parent) = new.parent_id {
+                if try!(exceeds_derivative_limit(&*conn, parent, 1)) {
+                    return Ok(None);
+                }
+            }
+            diesel::insert(&new).into(images).get_result::<Image>(&*conn).map(|i| Some((i, true)))
+        });
+
+        match res {
+            Ok(None) => {
+                try!(remove_stored_file(new.host_type, &new.path));
+                Err(error::FurryError::DerivativeLimit {
+                    id: new.parent_id.unwrap_or(0),
+                    max: MAX_DERIVATIVES.unwrap_or(0),
+                })
+            }
+            Ok(Some((i, created))) => {
+                // Inline images that encode to the same bytes share their path, there is no file to discard then
+                if !created && i.path != new.path {
+                    try!(remove_stored_file(new.host_type, &new.path));
+                }
+                Ok((i, created))
+            }
+            Err(TransactionError::UserReturnedError(Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _))) => {
+                try!(remove_stored_file(new.host_type, &new.path));
+                lookup(&*conn).map_err(|e| e.into())
+                    .and_then(|x| x.ok_or(error::FurryError::NotFound))
+                    .map(|i| (i, false))
+            }
+            Err(TransactionError::CouldntCreateTransaction(e)) | Err(TransactionError::UserReturnedError(e)) => {
+                try!(remove_stored_file(new.host_type, &new.path));
+                Err(e.into())
+            }
+        }
+    }
+
+    pub fn get_format(&self) -> Result<ImageFormat, error::FurryError> {
+        ImageFormat::from_i32(self.format)
+    }
+
+    /// The format, unless it is one we can't make derivatives in
+    fn resizable_format(&self) -> Result<ImageFormat, error::FurryError> {
+        match try!(self.get_format()) {
+            ImageFormat::Unknown(i) => Err(error::FurryError::InvalidImageFormat(i)),
+            format => Ok(format),
+        }
+    }
+
+    pub fn get_resize_mode(&self) -> Result<ResizeMode, error::FurryError> {
+        ResizeMode::from_parts(self.resize_mode, self.background)
+    }
+
+    pub fn content_hash(&self) -> Option<&str> {
+        self.content_hash.as_ref().map(|x| &x[..])
+    }
+
+    /// See `perceptual_hash`, images stored before it was computed have none
+    pub fn phash(&self) -> Option<i64> {
+        self.phash
+    }
+
+    /// Placeholder background to show while the image loads, see `accent_color`
+    pub fn accent_rgb(&self) -> Option<(u8, u8, u8)> {
+        self.accent_color.map(|c| ((c >> 16) as u8, (c >> 8) as u8, c as u8))
+    }
+
+    /// Whether the image has any transparent pixels, images stored before this was tracked always say no
+    pub fn has_alpha(&self) -> bool {
+        self.has_alpha
+    }
+
+    /// A name like `{file hash}.{extension}` that changes whenever the stored bytes do, for URLs that can be
+    /// cached forever
+    ///
+    /// Re-encoding keeps the pixels and so the content hash, which is why this goes by the bytes instead. Images
+    /// stored before their file hash was tracked have none, see `find_by_hashed_filename` for the way back.
+    pub fn hashed_filename(&self) -> Option<String> {
+        let ext = match self.get_format() {
+            Ok(f) => f.as_str(),
+            Err(_) => return None,
+        };
+        self.file_hash.as_ref().map(|hash| format!("{}.{}", hash, ext))
+    }
+
+    /// See `quality_score`, images stored before it was tracked have none
+    pub fn quality_score(&self) -> Option<f32> {
+        self.quality_score
+    }
+
+    /// Width divided by height, placeholder rows without dimensions count as square
+    pub fn aspect_ratio(&self) -> f64 {
+        if self.width <= 0 || self.height <= 0 {
+            return 1.0;
+        }
+        self.width as f64 / self.height as f64
+    }
+
+    pub fn orientation(&self) -> Orientation {
+        use std::cmp::Ordering;
+        match self.width.cmp(&self.height) {
+            Ordering::Less => Orientation::Portrait,
+            Ordering::Greater => Orientation::Landscape,
+            Ordering::Equal => Orientation::Square,
+        }
+    }
+
+    /// Blurred placeholder, see `blurhash`, decode it with the image's width and height to keep the aspect ratio
+    pub fn blurhash(&self) -> Option<&str> {
+        self.blurhash.as_ref().map(|x| &x[..])
+    }
+
+    pub fn created_at_utc(&self) -> DateTime<Utc> {
+        helper::pg_timestamp_to_utc(&self.created_at)
+    }
+
+    pub fn updated_at_utc(&self) -> DateTime<Utc> {
+        helper::pg_timestamp_to_utc(&self.updated_at)
+    }
+
+    pub fn last_accessed_at_utc(&self) -> DateTime<Utc> {
+        helper::pg_timestamp_to_utc(&self.last_accessed_at)
+    }
+
+    pub fn captured_at_utc(&self) -> Option<DateTime<Utc>> {
+        self.captured_at.as_ref().map(helper::pg_timestamp_to_utc)
+    }
+
+    /// Size of the encoded image in bytes
+    ///
+    /// Rows created before this was tracked report 0, the migration backfills the inline ones and local
+    /// ones can be fixed up with the size of their file, e.g. `stat -c %s` on everything in the uploads dir
+    pub fn byte_size(&self) -> i64 {
+        self.byte_size
+    }
+}
+
+impl Serialize for Image {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::{Error, SerializeStruct};
+
+        let url = try!(self.get_path().map_err(S::Error::custom));
+        let format = try!(self.get_format().map_err(S::Error::custom));
+
+        let mut state = try!(serializer.serialize_struct("Image", 8));
+        try!(state.serialize_field("id", &self.id));
+        try!(state.serialize_field("url", &url));
+        try!(state.serialize_field("width", &self.width));
+        try!(state.serialize_field("height", &self.height));
+        try!(state.serialize_field("format", format.as_str()));
+        try!(state.serialize_field("parent_id", &self.parent_id));
+        try!(state.serialize_field("created_at", &self.created_at_utc().to_rfc3339()));
+        try!(state.serialize_field("updated_at", &self.updated_at_utc().to_rfc3339()));
+        state.end()
+    }
+}
+
+#[derive(Clone, Debug)]
+#[derive(Insertable)]
+#[table_name="images"]
+pub struct NewImage {
+    host_type: i32,
+    path: String,
+    width: i32,
+    height: i32,
+    parent_id: Option<i64>,
+    wanted_height: Option<i32>,
+    wanted_width:  Option<i32>,
+    format: i32,
+    resize_mode: i32,
+    content_hash: Option<String>,
+    byte_size: i64,
+    owner_id: Option<i64>,
+    phash: Option<i64>,
+    accent_color: Option<i32>,
+    blurhash: Option<String>,
+    has_alpha: bool,
+    transform: i32,
+    background: i32,
+    captured_at: Option<diesel::data_types::PgTimestamp>,
+    quality_score: Option<f32>,
+    file_hash: Option<String>,
+}
+
+impl NewImage {
+    /// A placeholder row, size and format are left at 0, use `from_local_path` for actual files
+    pub fn new(typ: ImageType, path: &str) -> NewImage {
+        NewImage {
+            host_type: typ.as_i32(),
+            path: path.to_string(),
+            width: 0,
+            height: 0,
+            parent_id: None,
+            wanted_height: None,
+            wanted_width:  None,
+            format: 0,
+            resize_mode: ResizeMode::Fit.as_i32(),
+            content_hash: None,
+            byte_size: 0,
+            owner_id: None,
+            phash: None,
+            accent_color: None,
+            blurhash: None,
+            has_alpha: false,
+            transform: Transform::None as i32,
+            background: 0,
+            captured_at: None,
+            quality_score: None,
+            file_hash: None,
+        }
+    }
+
+    /// Registers an already existing file, `path` is relative to the upload root like every stored path
+    /// and has to point into the upload directory
+    ///
+    /// The file gets served as it is, so one that carries metadata like EXIF GPS positions is overwritten with
+    /// a re-encoded copy of its upright pixels first.
+    pub fn from_local_path(path: &str) -> Result<NewImage, error::FurryError> {
+        let full = try!(resolve_local(path));
+        let mut original = Vec::new();
+        try!(File::open(&full).and_then(|mut f| f.read_to_end(&mut original)).map_err(|e| file_error("read", &full, e)));
+
+        let fmt = try!(guess_allowed_format(&original));
+        let format = try!(ImageFormat::from_image_format(fmt));
+        // `decode` turns files with an orientation upright the same way
+        let img = orient(try!(image::load_from_memory_with_format(&original, fmt)), &original);
+        let dims = img.dimensions();
+
+        let bytes = if strip_metadata(original.clone(), format) != original {
+            // Stripping alone would lose the orientation the dimensions above already are in
+            let (_, encoded) = try!(encode_for_storage(&img, format, EncodeOptions::new()));
+            let (dir, name) = match (full.parent(), full.file_name().and_then(|n| n.to_str())) {
+                (Some(dir), Some(name)) => (dir, name),
+                _ => return Err(error::FurryError::PathEscape(path.to_string())),
+            };
+            try!(write_atomically(dir, name, &encoded));
+            encoded
+        } else {
+            original.clone()
+        };
+
+        let mut new = NewImage::new(ImageType::Local, path);
+        new.width = dims.0 as i32;
+        new.height = dims.1 as i32;
+        new.format = format.as_i32();
+        new.content_hash = Some(content_hash(&img));
+        new.byte_size = bytes.len() as i64;
+        new.file_hash = Some(bytes_hash(&bytes));
+        new.phash = Some(perceptual_hash(&img));
+        new.accent_color = Some(accent_color(&img));
+        new.blurhash = Some(blurhash(&img));
+        new.has_alpha = has_alpha(&img);
+        new.quality_score = Some(quality_score(&img));
+        new.captured_at = captured_at(&original);
+        Ok(new)
+    }
+
+    /// Stores uploaded bytes the client claims to be `declared` like a regular upload
+    pub fn from_bytes(bytes: &[u8], declared: ImageFormat, suffix: &str) -> Result<NewImage, error::FurryError> {
+        let fmt = try!(detect_format(bytes, declared));
+        let img = try!(image::load_from_memory_with_format(bytes, fmt));
+        NewImage::create_from_upload(bytes, img, fmt, suffix, EncodeOptions::new())
+    }
+
+    /// Stores the image in a `data:image/...;base64,...` URI, which has to be in the format its MIME type says
+    pub fn from_data_uri(uri: &str) -> Result<NewImage, error::FurryError> {
+        let uri = uri.trim();
+        if !uri.starts_with("data:") {
+            return Err(error::FurryError::InvalidDataUri);
+        }
+        let (header, payload) = match uri.find(',') {
+            Some(pos) => (&uri[5..pos], &uri[pos + 1..]),
+            None => return Err(error::FurryError::InvalidDataUri),
+        };
+
+        // Only base64 payloads make sense for binary data, the percent encoded kind is left alone
+        let mut params = header.split(';');
+        let mime = params.next().unwrap_or("");
+        if !params.any(|p| p.trim() == "base64") {
+            return Err(error::FurryError::InvalidDataUri);
+        }
+
+        let declared = try!(ImageFormat::from_mime_type(mime));
+        let bytes = try!(payload.from_base64().map_err(|_| error::FurryError::InvalidDataUri));
+        NewImage::from_bytes(&bytes, declared, "paste")
+    }
+
+    /// Reads an upload from a stream and stores it encoded with `opts`, at most `UPLOAD_MAX_SIZE` bytes are read
+    pub fn from_reader<R: Read>(r: R, opts: EncodeOptions) -> Result<NewImage, error::FurryError> {
+        let mut buffer = Vec::new();
+        try!(r.take(UPLOAD_MAX_SIZE + 1).read_to_end(&mut buffer));
+        if buffer.len() as u64 > UPLOAD_MAX_SIZE {
+            return Err(error::FurryError::UploadTooLarge(UPLOAD_MAX_SIZE));
+        }
+
+        let fmt = try!(guess_allowed_format(&buffer));
+        let img = try!(image::load_from_memory_with_format(&buffer, fmt));
+        NewImage::create_from_upload(&buffer, img, fmt, "upload", opts)
+    }
+
+    /// Downloads the image behind `url` and stores it like a regular upload
+    pub fn from_url(url: &str) -> Result<NewImage, error::FurryError> {
+        use std::time::Duration;
+        use hyper::{self, Client, Url};
+        use hyper::client::RedirectPolicy;
+        use hyper::header::{ContentLength, Location};
+        use hyper::status::StatusCode;
+
+        let mut client = Client::new();
+        client.set_read_timeout(Some(Duration::from_secs(FETCH_TIMEOUT_SECS)));
+        client.set_write_timeout(Some(Duration::from_secs(FETCH_TIMEOUT_SECS)));
+        // Redirects are followed by hand so every hop gets checked before we connect to it
+        client.set_redirect_policy(RedirectPolicy::FollowNone);
+
+        let mut target = try!(Url::parse(url).map_err(hyper::Error::Uri));
+        try!(check_fetch_target(&target));
+        let mut res = try!(client.get(target.clone()).send());
+        let mut redirects = 0;
+        while [301, 302, 303, 307, 308].contains(&res.status.to_u16()) {
+            let location = match res.headers.get::<Location>() {
+                Some(&Location(ref loc)) if redirects < FETCH_MAX_REDIRECTS => loc.clone(),
+                _ => return Err(error::FurryError::FetchStatus(res.status)),
+            };
+            target = try!(target.join(&location).map_err(hyper::Error::Uri));
+            try!(check_fetch_target(&target));
+            res = try!(client.get(target.clone()).send());
+            redirects += 1;
+        }
+
+        if res.status != StatusCode::Ok {
+            return Err(error::FurryError::FetchStatus(res.status));
+        }
+        if let Some(&ContentLength(len)) = res.headers.get::<ContentLength>() {
+            if len > FETCH_MAX_SIZE {
+                return Err(error::FurryError::FetchTooLarge(FETCH_MAX_SIZE));
+            }
+        }
+
+        let mut buffer = Vec::new();
+        try!(res.take(FETCH_MAX_SIZE + 1).read_to_end(&mut buffer));
+        if buffer.len() as u64 > FETCH_MAX_SIZE {
+            return Err(error::FurryError::FetchTooLarge(FETCH_MAX_SIZE));
+        }
+
+        let fmt = try!(guess_allowed_format(&buffer));
+        let img = try!(image::load_from_memory_with_format(&buffer, fmt));
+        NewImage::create_from_upload(&buffer, img, fmt, "url", EncodeOptions::new())
+    }
+
+    pub fn create_from_image_with_size(img: &Image, width: i32, height: i32) -> Result<NewImage, error::FurryError> {
+        NewImage::create_from_image_with_options(img, width, height, ResizeOptions::new())
+    }
+
+    pub fn create_from_image_with_options(img: &Image, width: i32, height: i32, opts: ResizeOptions) -> Result<NewImage, error::FurryError> {
+        try!(check_requested_size(width, height));
+        try!(img.resizable_format());
+        try!(check_dimensions(img.width as u32, img.height as u32));
+
+        if let ImageFormat::GIF = try!(img.get_format()) {
+            if try!(img.is_animated()) {
+                return NewImage::create_from_animated(img, width, height, opts);
+            }
+        }
+
+        // A crop cuts out pixels at the original scale, a shrunken source would give the wrong region
+        let small = match opts.mode {
+            ResizeMode::Crop => None,
+            _ => try!(img.decode_downscaled(width as u32, height as u32)),
+        };
+        let source = match small {
+            Some(small) => Arc::new(small),
+            None => try!(img.decode_cached()),
+        };
+        NewImage::create_from_decoded(img, &source, width, height, opts)
+    }
+
+    /// Resizes all frames of the animated GIF `img`, a plain decode would only keep the first one
+    fn create_from_animated(img: &Image, width: i32, height: i32, opts: ResizeOptions) -> Result<NewImage, error::FurryError> {
+        let bytes = try!(img.bytes());
+        let (buf, dims, hash) = try!(resize_animated_gif(&bytes, width as u32, height as u32, opts));
+        let mut image = try!(NewImage::store_encoded(buf, dims, ImageFormat::GIF, &format!("orig_{}", img.id)[..],
+                                                     *INLINE_MAX_DIM, hash, Store::Derivatives));
+        // Scaling the frames keeps whatever transparency they had
+        image.has_alpha = img.has_alpha;
+        Ok(image.with_parent(img.id)
+                .with_owner_of(img)
+                .with_wanted_size(width, height)
+                .with_resize_mode(opts.mode))
+    }
+
+    /// Like `create_from_image_with_options` but with `source` being the already decoded `img`
+    pub fn create_from_decoded(img: &Image, source: &DynamicImage, width: i32, height: i32, opts: ResizeOptions)
+        -> Result<NewImage, error::FurryError>
+    {
+        try!(check_requested_size(width, height));
+        NewImage::create_from_resized(img, &opts.apply(source, width as u32, height as u32), width, height, opts)
+    }
+
+    fn create_from_resized(img: &Image, resized: &DynamicImage, width: i32, height: i32, opts: ResizeOptions)
+        -> Result<NewImage, error::FurryError>
+    {
+        let image = try!(
+            NewImage::create_from_dynamic_image_in(resized, &format!("orig_{}", img.id)[..], try!(img.get_format()),
+                                                   *INLINE_MAX_DIM, opts.encoding, Store::Derivatives)
+        );
+        Ok(image.with_parent(img.id)
+                .with_owner_of(img)
+                .with_wanted_size(width, height)
+                .with_resize_mode(opts.mode))
+    }
+
+    pub fn with_parent(mut self, id: i64) -> NewImage {
+        self.parent_id = Some(id);
+        self
+    }
+
+    pub fn with_owner(mut self, id: i64) -> NewImage {
+        self.owner_id = Some(id);
+        self
+    }
+
+    /// When the photo was taken, for images created from decoded pixels that lost their EXIF data
+    pub fn with_captured_at(mut self, at: Option<diesel::data_types::PgTimestamp>) -> NewImage {
+        self.captured_at = at;
+        self
+    }
+
+    fn with_owner_of(mut self, img: &Image) -> NewImage {
+        self.owner_id = img.owner_id;
+        self
+    }
+
+    pub fn with_wanted_size(mut self, width: i32, height: i32) -> NewImage {
+        self.wanted_width = Some(width);
+        self.wanted_height = Some(height);
+        self
+    }
+
+    pub fn with_resize_mode(mut self, mode: ResizeMode) -> NewImage {
+        self.resize_mode = mode.as_i32();
+        self.background = mode.background_i32();
+        self
+    }
+
+    pub fn create_from_dynamic_image(img: &DynamicImage, suffix: &str, fmt: image::ImageFormat) -> Result<NewImage, error::FurryError> {
+        NewImage::create_from_dynamic_image_with_threshold(img, suffix, fmt, *INLINE_MAX_DIM)
+    }
+
+    pub fn create_from_dynamic_image_with_threshold(img: &DynamicImage, suffix: &str, fmt: image::ImageFormat,
+                                                    inline_max_dim: Option<u32>) -> Result<NewImage, error::FurryError> {
+        NewImage::create_from_dynamic_image_with(img, suffix, fmt, inline_max_dim, EncodeOptions::new(),
+                                                 *PNG_TRANSCODE_MIN_BYTES)
+    }
+
+    /// Like `create_from_dynamic_image` but encodes JPEGs with the given quality between 1 and 100
+    pub fn create_from_dynamic_image_with_quality(img: &DynamicImage, suffix: &str, fmt: image::ImageFormat,
+                                                  quality: u8) -> Result<NewImage, error::FurryError> {
+        NewImage::create_from_dynamic_image_with_encoding(img, suffix, fmt, EncodeOptions::new().with_jpeg_quality(quality))
+    }
+
+    /// Like `create_from_dynamic_image` but with control over how the encoder of `fmt` is set up
+    pub fn create_from_dynamic_image_with_encoding(img: &DynamicImage, suffix: &str, fmt: image::ImageFormat,
+                                                   opts: EncodeOptions) -> Result<NewImage, error::FurryError> {
+        NewImage::create_from_dynamic_image_with(img, suffix, fmt, *INLINE_MAX_DIM, opts, *PNG_TRANSCODE_MIN_BYTES)
+    }
+
+    /// Stores the upload `bytes` that decoded to `img`, animated GIFs keep all of their frames
+    fn create_from_upload(bytes: &[u8], img: DynamicImage, fmt: image::ImageFormat, suffix: &str,
+                          opts: EncodeOptions) -> Result<NewImage, error::FurryError> {
+        if try!(is_animated_gif(bytes)) {
+            return NewImage::from_animated_gif(bytes, &img, suffix);
+        }
+        let mut new = try!(NewImage::create_from_dynamic_image_with_encoding(&orient(img, bytes), suffix, fmt, opts));
+        new.captured_at = captured_at(bytes);
+        Ok(new)
+    }
+
+    /// Stores the animated GIF in `bytes` as it is, `first` being its decoded first frame
+    ///
+    /// Encoding from decoded pixels would only keep that one frame. The metrics are taken from it as well, the
+    /// content hash covers the whole file instead.
+    pub fn from_animated_gif(bytes: &[u8], first: &DynamicImage, suffix: &str) -> Result<NewImage, error::FurryError> {
+        let dims = first.dimensions();
+        try!(check_dimensions(dims.0, dims.1));
+        let mut new = try!(NewImage::store_encoded(bytes.to_vec(), dims, ImageFormat::GIF, suffix, *INLINE_MAX_DIM,
+                                                   bytes_hash(bytes), Store::Uploads));
+        new.phash = Some(perceptual_hash(first));
+        new.accent_color = Some(accent_color(first));
+        new.blurhash = Some(blurhash(first));
+        new.quality_score = Some(quality_score(first));
+        new.has_alpha = has_alpha(first);
+        Ok(new)
+    }
+
+    /// Stores the image as `format`, which unlike `image::ImageFormat` can be AVIF
+    ///
+    /// Without an AVIF encoder those get stored as `IMAGE_AVIF_FALLBACK` instead of failing the upload.
+    pub fn create_from_dynamic_image_as(img: &DynamicImage, suffix: &str, format: ImageFormat)
+        -> Result<NewImage, error::FurryError>
+    {
+        NewImage::create_from_dynamic_image_in(img, suffix, format, *INLINE_MAX_DIM, EncodeOptions::new(), Store::Uploads)
+    }
+
+    /// Opaque PNGs encoding to more than `png_transcode_min` bytes get stored as JPEG instead
+    fn create_from_dynamic_image_with(img: &DynamicImage, suffix: &str, fmt: image::ImageFormat,
+                                      inline_max_dim: Option<u32>, opts: EncodeOptions,
+                                      png_transcode_min: Option<usize>)
+        -> Result<NewImage, error::FurryError>
+    {
+        let format = try!(ImageFormat::from_image_format(storage_format(fmt)));
+
+        // Large photographic PNGs are a lot smaller as JPEG, transparency would get lost though
+        if let (ImageFormat::PNG, Some(min)) = (format, png_transcode_min) {
+            if !has_alpha(img) {
+                let (format, buf) = try!(encode_for_storage(img, format, opts));
+                if buf.len() <= min {
+                    // Small enough already, no need to encode it a second time
+                    return NewImage::store_dynamic_image(img, format, buf, suffix, inline_max_dim, Store::Uploads);
+                }
+                return NewImage::create_from_dynamic_image_in(img, suffix, ImageFormat::JPEG, inline_max_dim, opts,
+                                                              Store::Uploads);
+            }
+        }
+
+        NewImage::create_from_dynamic_image_in(img, suffix, format, inline_max_dim, opts, Store::Uploads)
+    }
+
+    fn create_from_dynamic_image_in(img: &DynamicImage, suffix: &str, format: ImageFormat,
+                                    inline_max_dim: Option<u32>, opts: EncodeOptions, store: Store)
+        -> Result<NewImage, error::FurryError>
+    {
+        let (format, buf) = try!(encode_for_storage(img, format, opts));
+        NewImage::store_dynamic_image(img, format, buf, suffix, inline_max_dim, store)
+    }
+
+    /// Stores `buf`, which is `img` already encoded as `format`, along with the metrics taken from `img`
+    fn store_dynamic_image(img: &DynamicImage, format: ImageFormat, buf: Vec<u8>, suffix: &str,
+                           inline_max_dim: Option<u32>, store: Store) -> Result<NewImage, error::FurryError>
+    {
+        let dims = img.dimensions();
+        let mut new = try!(NewImage::store_encoded(buf, dims, format, suffix, inline_max_dim, content_hash(img), store));
+        // The metrics are there to compare and moderate uploads, derivatives would only pay for them on every request
+        let original = match store {
+            Store::Uploads => true,
+            Store::Derivatives => false,
+        };
+        if original {
+            new.phash = Some(perceptual_hash(img));
+            new.accent_color = Some(accent_color(img));
+            new.blurhash = Some(blurhash(img));
+            new.quality_score = Some(quality_score(img));
+        }
+        new.has_alpha = format.supports_alpha() && has_alpha(img);
+        Ok(new)
+    }
+
+    /// Stores already encoded image bytes either inline, in the S3 bucket if one is configured or as a new file
+    /// in `store`
+    fn store_encoded(buf: Vec<u8>, dims: (u32, u32), format: ImageFormat, suffix: &str, inline_max_dim: Option<u32>,
+                     hash: String, store: Store) -> Result<NewImage, error::FurryError> {
+        let inline = match inline_max_dim {
+            Some(max) => dims.0 < max && dims.1 < max && buf.len() <= INLINE_MAX_BYTES,
+            None => false,
+        };
+
+        if let ImageFormat::BMP = format {
+            warn!("Storing {}x{} image as uncompressed BMP ({} bytes)", dims.0, dims.1, buf.len());
+        }
+
+        let backend: &ImageStore = if inline {
+            &BASE64_STORE
+        } else if s3::enabled() {
+            &S3_STORE
+        } else {
+            &LOCAL_STORE
+        };
+
+        // The random part keeps uploads of the same size from overwriting each other
+        let token = thread_rng().gen_ascii_chars().take(16).collect::<String>();
+        let filename = format!("{}_{}-{}-{}.{}", dims.0, dims.1, token, suffix, format.as_str());
+        let stored = try!(backend.write(&buf, &StoreMeta { filename: &filename, format: format, store: store }));
+
+        Ok(NewImage {
+            path: stored.path,
+            host_type: stored.host_type.as_i32(),
+            width: dims.0 as i32,
+            height: dims.1 as i32,
+            parent_id: None,
+            wanted_height: None,
+            wanted_width: None,
+            format: format.as_i32(),
+            resize_mode: ResizeMode::Fit.as_i32(),
+            content_hash: Some(hash),
+            file_hash: Some(bytes_hash(&buf)),
+            byte_size: buf.len() as i64,
+            owner_id: None,
+            phash: None,
+            accent_color: None,
+            blurhash: None,
+            has_alpha: false,
+            transform: Transform::None as i32,
+            background: 0,
+            captured_at: None,
+            quality_score: None,
+        })
+    }
+
+    pub fn content_hash(&self) -> Option<&str> {
+        self.content_hash.as_ref().map(|x| &x[..])
+    }
+
+    fn remove_file(&self) -> Result<(), error::FurryError> {
+        remove_stored_file(self.host_type, &self.path)
+    }
+}
+
+/// Finds an image by id, soft deleted ones are left out
+pub fn find(uid: i64) -> Result<Option<Image>, error::FurryError> {
+    use diesel::prelude::*;
+    use models::schema::images::dsl::*;
+
+    let conn = try!(database::conn());
+    images.limit(1).filter(id.eq(uid)).filter(deleted_at.is_null())
+         .get_result::<models::image::Image>(&*conn).optional().map_err(|e| e.into())
+}
+
+/// Looks up all ids in a single query, returned in the order of `ids` with missing and soft deleted ones left out
+pub fn find_many(ids: &[i64]) -> Result<Vec<Image>, error::FurryError> {
+    use diesel::prelude::*;
+    use models::schema::images::dsl::*;
+
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let conn = try!(database::conn());
+    let mut found = try!(images.filter(id.eq_any(ids.to_vec())).filter(deleted_at.is_null())
+         .get_results::<models::image::Image>(&*conn));
+    found.sort_by_key(|i| ids.iter().position(|&x| x == i.id));
+    Ok(found)
+}
+
+/// Like `find` but also returns soft deleted images
+pub fn find_include_deleted(uid: i64) -> Result<Option<Image>, error::FurryError> {
+    use diesel::prelude::*;
+    use models::schema::images::dsl::*;
+
+    let conn = try!(database::conn());
+    images.limit(1).filter(id.eq(uid))
+         .get_result::<models::image::Image>(&*conn).optional().map_err(|e| e.into())
+}
+
+pub fn find_all(limit: i64, offset: i64) -> Result<Vec<Image>, error::FurryError> {
+    ImageFilter::new(limit, offset).run()
+}
+
+/// Original images created from `start` up to but excluding `end`, newest first
+pub fn find_between(start: DateTime<Utc>, end: DateTime<Utc>, limit: i64, offset: i64)
+    -> Result<Vec<Image>, error::FurryError>
+{
+    ImageFilter::new(limit, offset).with_created_between(start, end).run()
+}
+
+/// Counts the original images, derivatives and soft deleted ones are left out
+pub fn count() -> Result<i64, error::FurryError> {
+    use diesel::prelude::*;
+    use models::schema::images::dsl::*;
+
+    let conn = try!(database::conn());
+    images.filter(parent_id.is_null()).filter(deleted_at.is_null()).count()
+         .get_result::<i64>(&*conn).map_err(|e| e.into())
+}
+
+/// Lists images newest first, derivatives and soft deleted images are left out unless asked for
+pub struct ImageFilter {
+    limit: i64,
+    offset: i64,
+    derivatives: bool,
+    deleted: bool,
+    created: Option<(diesel::data_types::PgTimestamp, diesel::data_types::PgTimestamp)>,
+}
+
+impl ImageFilter {
+    pub fn new(limit: i64, offset: i64) -> ImageFilter {
+        ImageFilter {
+            limit: limit,
+            offset: offset,
+            derivatives: false,
+            deleted: false,
+            created: None,
+        }
+    }
+
+    pub fn with_derivatives(mut self) -> ImageFilter {
+        self.derivatives = true;
+        self
+    }
+
+    pub fn with_deleted(mut self) -> ImageFilter {
+        self.deleted = true;
+        self
+    }
+
+    pub fn with_created_between(mut self, start: DateTime<Utc>, end: DateTime<Utc>) -> ImageFilter {
+        self.created = Some((helper::utc_to_pg_timestamp(&start), helper::utc_to_pg_timestamp(&end)));
+        self
+    }
+
+    pub fn run(self) -> Result<Vec<Image>, error::FurryError> {
+        use diesel::prelude::*;
+        use models::schema::images::dsl::*;
+
+        let mut query = images.limit(self.limit).offset(self.offset).into_boxed();
+
+        if !self.derivatives {
+            query = query.filter(parent_id.is_null());
+        }
+
+        if !self.deleted {
+            query = query.filter(deleted_at.is_null());
+        }
+
+        if let Some((start, end)) = self.created {
+            query = query.filter(created_at.ge(start)).filter(created_at.lt(end));
+        }
+
+        query = query.order(created_at.desc());
+
+        let conn = try!(database::conn());
+        query.get_results::<models::image::Image>(&*conn).map_err(|e| e.into())
+    }
+}
+
+/// The original images uploaded by the given user, newest first
+pub fn find_by_owner(owner: i64, limit: i64, offset: i64) -> Result<Vec<Image>, error::FurryError> {
+    use diesel::prelude::*;
+    use models::schema::images::dsl::*;
+
+    let conn = try!(database::conn());
+    images.filter(owner_id.eq(owner)).filter(parent_id.is_null()).filter(deleted_at.is_null())
+         .order(created_at.desc()).limit(limit).offset(offset)
+         .get_results::<models::image::Image>(&*conn).map_err(|e| e.into())
+}
+
+/// Original images whose perceptual hash differs from `hash` in at most `max_distance` bits, closest first
+pub fn find_similar(hash: i64, max_distance: u32) -> Result<Vec<Image>, error::FurryError> {
+    use diesel::prelude::*;
+    use models::schema::images::dsl::*;
+
+    let conn = try!(database::conn());
+    // Postgres has no popcount, the hashes are small enough to compare here
+    let hashes = try!(images.select((id, phash))
+        .filter(parent_id.is_null()).filter(deleted_at.is_null()).filter(phash.is_not_null())
+        .load::<(i64, Option<i64>)>(&*conn));
+
+    let ids = hashes.into_iter()
+        .filter_map(|(i, p)| p.map(|p| (i, p)))
+        .filter(|&(_, p)| hash_distance(hash, p) <= max_distance)
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut found = try!(images.filter(id.eq_any(ids)).get_results::<models::image::Image>(&*conn));
+    found.sort_by_key(|i| i.phash.map(|p| hash_distance(hash, p)));
+    Ok(found)
+}
+
+/// Finds an original image with the given content hash
+pub fn find_by_hash(hash: &str) -> Result<Option<Image>, error::FurryError> {
+    use diesel::prelude::*;
+    use models::schema::images::dsl::*;
+
+    let conn = try!(database::conn());
+    images.limit(1).filter(content_hash.eq(hash)).filter(parent_id.is_null()).filter(deleted_at.is_null()).order(id)
+         .get_result::<models::image::Image>(&*conn).optional().map_err(|e| e.into())
+}
+
+/// Finds the image `Image::hashed_filename` gave `name`, originals and derivatives alike
+///
+/// Images with the same bytes are interchangeable, so any of them is fine.
+pub fn find_by_hashed_filename(name: &str) -> Result<Option<Image>, error::FurryError> {
+    use diesel::prelude::*;
+    use models::schema::images::dsl::*;
+
+    let (hash, ext) = match name.rfind('.') {
+        Some(pos) => (&name[..pos], &name[pos + 1..]),
+        None => return Ok(None),
+    };
+    let fmt = match ext.parse::<ImageFormat>() {
+        Ok(f) => f,
+        Err(_) => return Ok(None),
+    };
+
+    let conn = try!(database::conn());
+    images.limit(1).filter(file_hash.eq(hash)).filter(format.eq(fmt.as_i32())).filter(deleted_at.is_null()).order(id)
+         .get_result::<models::image::Image>(&*conn).optional().map_err(|e| e.into())
+}
+
+pub fn find_from_image(uid: i64, w: i32, h: i32) -> Result<Option<Image>, error::FurryError> {
+    find_from_image_with_mode(uid, w, h, ResizeMode::Fit)
+}
+
+/// Finds a derivative of the given size and mode, whatever format it was stored in
+pub fn find_from_image_with_mode(uid: i64, w: i32, h: i32, mode: ResizeMode) -> Result<Option<Image>, error::FurryError> {
+    let conn = try!(database::conn());
+    let found = try!(query_from_image(&*conn, uid, w, h, mode, None));
+    Ok(touch(&*conn, found))
+}
+
+pub fn find_from_image_with_format(uid: i64, w: i32, h: i32, mode: ResizeMode, fmt: ImageFormat)
+    -> Result<Option<Image>, error::FurryError>
+{
+    let conn = try!(database::conn());
+    let found = try!(query_from_image(&*conn, uid, w, h, mode, Some(fmt)));
+    Ok(touch(&*conn, found))
+}
+
+/// The derivative of the given image made with `transform`
+pub fn find_transformed(uid: i64, t: Transform, fmt: ImageFormat) -> Result<Option<Image>, error::FurryError> {
+    let conn = try!(database::conn());
+    let found = try!(query_transformed(&*conn, uid, t, fmt));
+    Ok(touch(&*conn, found))
+}
+
+/// Records that a derivative got served, at most once per `ACCESS_TOUCH_INTERVAL_SECS` so not every read
+/// turns into a write
+fn touch(conn: &PgConnection, found: Option<Image>) -> Option<Image> {
+    use diesel;
+    use diesel::prelude::*;
+    use models::schema::images::dsl::*;
+
+    if let Some(ref i) = found {
+        let now = Utc::now();
+        if (now - i.last_accessed_at_utc()).num_seconds() >= ACCESS_TOUCH_INTERVAL_SECS {
+            let res = diesel::update(images.filter(id.eq(i.id)))
+                .set(last_accessed_at.eq(helper::utc_to_pg_timestamp(&now)))
+                .execute(conn);
+            // Serving the derivative matters more than keeping it from being pruned
+            if let Err(e) = res {
+                warn!("Could not record access to image {}: {}", i.id, e);
+            }
+        }
+    }
+    found
+}
+
+/// Deletes derivatives that weren't served during the last `older_than` together with their files,
+/// returns how many got deleted
+pub fn prune_derivatives(older_than: ::std::time::Duration) -> Result<usize, error::FurryError> {
+    use chrono;
+    use diesel::prelude::*;
+    use models::schema::images::dsl::*;
+
+    let cutoff = Utc::now() - chrono::Duration::seconds(older_than.as_secs() as i64);
+    let conn = try!(database::conn());
+    let stale = try!(images.filter(parent_id.is_not_null())
+                     .filter(deleted_at.is_null())
+                     .filter(last_accessed_at.lt(helper::utc_to_pg_timestamp(&cutoff)))
+                     .load::<Image>(&*conn));
+
+    for i in &stale {
+        try!(i.delete(false));
+    }
+    Ok(stale.len())
+}
+
+fn query_transformed(conn: &PgConnection, uid: i64, t: Transform, fmt: ImageFormat) -> diesel::QueryResult<Option<Image>> {
+    use diesel::prelude::*;
+    use models::schema::images::dsl::*;
+
+    images.limit(1)
+        .filter(parent_id.eq(uid))
+        .filter(deleted_at.is_null())
+        .filter(transform.eq(t as i32))
+        .filter(format.eq(fmt.as_i32()))
+        .get_result::<models::image::Image>(conn)
+        .optional()
+}
+
+fn query_from_image(conn: &PgConnection, uid: i64, w: i32, h: i32, mode: ResizeMode, fmt: Option<ImageFormat>)
+    -> diesel::QueryResult<Option<Image>>
+{
+    use diesel::prelude::*;
+    use models::schema::images::dsl::*;
+
+    // Every condition gets its own filter, an `or` next to them would not be grouped and could match
+    // derivatives of other images
+    let mut exact = images.limit(1)
+        .filter(parent_id.eq(uid))
+        .filter(deleted_at.is_null())
+        .filter(transform.eq(Transform::None as i32))
+        .filter(resize_mode.eq(mode.as_i32()))
+        .filter(background.eq(mode.background_i32()))
+        .filter(wanted_width.eq(w))
+        .filter(wanted_height.eq(h))
+        .into_boxed();
+
+    if let Some(f) = fmt {
+        exact = exact.filter(format.eq(f.as_i32()));
+    }
+
+    let exact = try!(exact.get_result::<models::image::Image>(conn).optional());
+
+    if exact.is_some() {
+        return Ok(exact);
+    }
+
+    // Derivatives from before the wanted size was stored can only be matched on their actual size
+    let mut legacy = images.limit(1)
+        .filter(parent_id.eq(uid))
+        .filter(deleted_at.is_null())
+        .filter(transform.eq(Transform::None as i32))
+        .filter(resize_mode.eq(mode.as_i32()))
+        .filter(background.eq(mode.background_i32()))
+        .filter(wanted_width.is_null())
+        .filter(width.eq(w))
+        .filter(height.eq(h))
+        .into_boxed();
+
+    if let Some(f) = fmt {
+        legacy = legacy.filter(format.eq(f.as_i32()));
+    }
+
+    legacy.get_result::<models::image::Image>(conn).optional()
+}
+
+/// Local images whose file is gone, or whose path points outside of the upload directory
+///
+/// The table is walked in batches so this works on any number of images, soft deleted ones are skipped.
+pub fn find_broken() -> Result<Vec<Image>, error::FurryError> {
+    use diesel::prelude::*;
+    use models::schema::images::dsl::*;
+
+    static BATCH_SIZE : i64 = 500;
+
+    let conn = try!(database::conn());
+    let mut broken = Vec::new();
+    let mut last = 0;
+    loop {
+        let batch = try!(images.filter(id.gt(last))
+            .filter(host_type.eq(ImageType::Local.as_i32()))
+            .filter(deleted_at.is_null())
+            .order(id.asc())
+            .limit(BATCH_SIZE)
+            .get_results::<models::image::Image>(&*conn));
+
+        let done = (batch.len() as i64) < BATCH_SIZE;
+        if let Some(i) = batch.last() {
+            last = i.id;
+        }
+
+        broken.extend(batch.into_iter().filter(|i| match resolve_local(&i.path) {
+            Ok(p) => !p.is_file(),
+            Err(_) => true,
+        }));
+
+        if done {
+            return Ok(broken);
+        }
+    }
+}
+
+/// Regenerates every derivative that isn't soft deleted from its parent with `opts`, see `Image::regenerate_with`
+///
+/// Returns how many were regenerated. Derivatives whose parent is gone or that fail to regenerate are logged
+/// and skipped.
+pub fn regenerate_all_derivatives(opts: ResizeOptions) -> Result<usize, error::FurryError> {
+    regenerate_derivatives_after(0, opts)
+}
+
+/// Like `regenerate_all_derivatives` but only for ids above `start`, to resume where an earlier run stopped
+///
+/// The table is walked in batches by id, the last id of each finished batch gets logged.
+pub fn regenerate_derivatives_after(start: i64, opts: ResizeOptions) -> Result<usize, error::FurryError> {
+    use diesel::prelude::*;
+    use models::schema::images::dsl::*;
+
+    static BATCH_SIZE : i64 = 100;
+
+    let conn = try!(database::conn());
+    let mut regenerated = 0;
+    let mut last = start;
+    loop {
+        let batch = try!(images.filter(id.gt(last))
+            .filter(parent_id.is_not_null())
+            .filter(deleted_at.is_null())
+            .order(id.asc())
+            .limit(BATCH_SIZE)
+            .get_results::<models::image::Image>(&*conn));
+
+        let done = (batch.len() as i64) < BATCH_SIZE;
+        if let Some(i) = batch.last() {
+            last = i.id;
+        }
+
+        for i in batch {
+            let pid = i.parent_id.unwrap_or(0);
+            let parent = match try!(find_include_deleted(pid)) {
+                Some(p) => p,
+                None => {
+                    warn!("Parent {} of derivative {} is missing, skipping it", pid, i.id);
+                    continue;
+                }
+            };
+            match i.regenerate_from(&parent, opts) {
+                Ok(()) => regenerated += 1,
+                Err(e) => error!("Could not regenerate derivative {}: {}", i.id, e),
+            }
+        }
+
+        info!("Regenerated {} derivatives up to id {}", regenerated, last);
+        if done {
+            return Ok(regenerated);
+        }
+    }
+}
+
+/// An image whose row disagrees with its file about how large it is
+pub struct DimensionMismatch {
+    pub image: Image,
+    /// What the file actually decodes to
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Decodes every image that isn't soft deleted and reports the ones with wrong dimensions in their row
+///
+/// Walks the table in batches like `find_broken`. Images that can't be decoded at all are logged and skipped,
+/// `find_broken` is the place to look for those.
+pub fn verify_all_dimensions() -> Result<Vec<DimensionMismatch>, error::FurryError> {
+    use diesel::prelude::*;
+    use models::schema::images::dsl::*;
+
+    static BATCH_SIZE : i64 = 100;
+
+    let conn = try!(database::conn());
+    let mut mismatches = Vec::new();
+    let mut last = 0;
+    loop {
+        let batch = try!(images.filter(id.gt(last))
+            .filter(deleted_at.is_null())
+            .order(id.asc())
+            .limit(BATCH_SIZE)
+            .get_results::<models::image::Image>(&*conn));
+
+        let done = (batch.len() as i64) < BATCH_SIZE;
+        if let Some(i) = batch.last() {
+            last = i.id;
+        }
+
+        for i in batch {
+            let dims = match i.decode() {
+                Ok(img) => img.dimensions(),
+                Err(e) => {
+                    warn!("Could not decode image {} to verify its dimensions: {}", i.id, e);
+                    continue;
+                }
+            };
+            if dims != (i.width as u32, i.height as u32) {
+                mismatches.push(DimensionMismatch { image: i, width: dims.0, height: dims.1 });
+            }
+        }
+
+        if done {
+            return Ok(mismatches);
+        }
+    }
+}
+
+/// Files in the upload directory no row points to, they are deleted unless `dry_run` is set
+///
+/// Returns the orphans as paths like the ones stored in rows. Files younger than an hour are left alone,
+/// they might belong to an upload that hasn't been inserted yet.
+pub fn cleanup_orphans(dry_run: bool) -> Result<Vec<String>, error::FurryError> {
+    use std::collections::HashSet;
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+    use diesel::prelude::*;
+    use models::schema::images::dsl::*;
+
+    let conn = try!(database::conn());
+    let known = try!(images.select(path).filter(host_type.eq(ImageType::Local.as_i32())).load::<String>(&*conn))
+        .iter().map(|p| stored_file(p).0).collect::<HashSet<_>>();
+
+    let mut stores = vec![Store::Uploads.location()];
+    if DERIVATIVE_DIR.is_some() {
+        stores.push(Store::Derivatives.location());
+    }
+
+    let grace = Duration::from_secs(60 * 60);
+    let mut orphans = Vec::new();
+    for (prefix, dir) in stores {
+        for entry in try!(fs::read_dir(&dir).map_err(|e| file_error("list", &dir, e))) {
+            let entry = try!(entry);
+            let meta = try!(entry.metadata());
+            if !meta.is_file() || known.contains(&entry.path()) {
+                continue;
+            }
+            let recent = meta.modified().ok()
+                .and_then(|m| SystemTime::now().duration_since(m).ok())
+                .map(|age| age < grace)
+                .unwrap_or(true);
+            if recent {
+                continue;
+            }
+
+            if !dry_run {
+                try!(fs::remove_file(entry.path()).map_err(|e| file_error("remove", &entry.path(), e)));
+            }
+            orphans.push(format!("{}/{}", prefix, entry.file_name().to_string_lossy()));
+        }
+    }
+    Ok(orphans)
+}
+
+/// Whether `parent` can't take `adding` more derivatives without going over `IMAGE_MAX_DERIVATIVES`
+fn exceeds_derivative_limit(conn: &PgConnection, parent: i64, adding: i64) -> diesel::QueryResult<bool> {
+    use diesel::prelude::*;
+    use models::schema::images::dsl::*;
+
+    match *MAX_DERIVATIVES {
+        Some(max) => images.filter(parent_id.eq(parent)).filter(deleted_at.is_null()).count()
+            .get_result::<i64>(conn).map(|n| n + adding > max),
+        None => Ok(false),
+    }
+}
+
+fn remove_stored_file(typ: i32, path: &str) -> Result<(), error::FurryError> {
+    try!(try!(ImageType::from_i32(typ)).store()).remove(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fetching_private_addresses_is_refused() {
+        for ip in &["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "0.0.0.0",
+                    "100.64.0.1", "::1", "fe80::1", "fd00::1", "::ffff:127.0.0.1"] {
+            assert!(!is_public_address(ip.parse().unwrap()), "{} counts as public", ip);
+        }
+        for ip in &["93.184.216.34", "8.8.8.8", "2606:2800:220:1::248"] {
+            assert!(is_public_address(ip.parse().unwrap()), "{} counts as private", ip);
+        }
+    }
+
+    #[test]
+    fn fetching_other_schemes_is_refused() {
+        let url = ::hyper::Url::parse("file:///etc/passwd").unwrap();
+        assert!(check_fetch_target(&url).is_err());
+    }
+
+    #[test]
+    fn image_format_parses_its_own_name() {
+        for fmt in &[ImageFormat::PNG, ImageFormat::GIF, ImageFormat::JPEG, ImageFormat::WEBP, ImageFormat::ICO,
+                     ImageFormat::BMP, ImageFormat::AVIF] {
+            assert_eq!(fmt.to_string().parse::<ImageFormat>().unwrap(), *fmt);
+        }
+        assert_eq!("JPG".parse::<ImageFormat>().unwrap(), ImageFormat::JPEG);
+        assert_eq!("Png".parse::<ImageFormat>().unwrap(), ImageFormat::PNG);
+        assert!("tiff".parse::<ImageFormat>().is_err());
+        assert!("".parse::<ImageFormat>().is_err());
+    }
+
+    fn rgba(w: u32, h: u32) -> Arc<DynamicImage> {
+        Arc::new(DynamicImage::ImageRgba8(image::ImageBuffer::new(w, h)))
+    }
+
+    #[test]
+    fn decode_cache_is_bounded_by_pixel_bytes() {
+        let mut cache = DecodeCache::new(3 * 100 * 100 * 4);
+        cache.insert(1, rgba(100, 100));
+        cache.insert(2, rgba(100, 100));
+        cache.insert(3, rgba(100, 100));
+        assert!(cache.get(1).is_some());
+
+        // Two slots worth of pixels push out the two least recently used entries
+        cache.insert(4, rgba(200, 100));
+        assert!(cache.contains(1));
+        assert!(!cache.contains(2));
+        assert!(!cache.contains(3));
+        assert!(cache.contains(4));
+        assert_eq!(cache.used, 3 * 100 * 100 * 4);
+    }
+
+    #[test]
+    fn decode_cache_skips_images_larger_than_itself() {
+        let mut cache = DecodeCache::new(100 * 100 * 4);
+        cache.insert(1, rgba(50, 50));
+        cache.insert(2, rgba(101, 100));
+        assert!(cache.contains(1));
+        assert!(!cache.contains(2));
+
+        cache.remove(1);
+        assert_eq!(cache.used, 0);
+    }
+
+    #[test]
+    fn sizes_snap_to_the_nearest_preset() {
+        let presets = [64, 128, 256, 512, 1024];
+        assert_eq!(nearest_of(&presets, 300), 256);
+        assert_eq!(nearest_of(&presets, 10), 64);
+        assert_eq!(nearest_of(&presets, 5000), 1024);
+        assert_eq!(nearest_of(&presets, 384), 512);
+        assert_eq!(nearest_of(&[], 300), 300);
+    }
+
+    #[test]
+    fn stored_integers_convert_to_formats_and_types() {
+        assert_eq!(ImageFormat::try_from(0).unwrap(), ImageFormat::PNG);
+        assert_eq!(ImageFormat::try_from(2).unwrap(), ImageFormat::JPEG);
+        assert_eq!(ImageFormat::try_from(6).unwrap(), ImageFormat::AVIF);
+        assert_eq!(ImageType::try_from(0).unwrap(), ImageType::Local);
+        assert_eq!(ImageType::try_from(1).unwrap(), ImageType::Base64);
+        assert_eq!(ImageType::try_from(2).unwrap(), ImageType::S3);
+
+        for fmt in &[ImageFormat::PNG, ImageFormat::GIF, ImageFormat::JPEG, ImageFormat::WEBP,
+                     ImageFormat::ICO, ImageFormat::BMP, ImageFormat::AVIF] {
+            assert_eq!(ImageFormat::from_i32(fmt.as_i32()).unwrap(), *fmt);
+        }
+        for ty in &[ImageType::Local, ImageType::Base64, ImageType::S3] {
+            assert_eq!(ImageType::from_i32(ty.as_i32()).unwrap(), *ty);
+        }
+    }
+
+    #[test]
+    fn out_of_range_integers_are_not_mistaken_for_known_values() {
+        match ImageFormat::try_from(-1) {
+            Ok(ImageFormat::Unknown(-1)) => (),
+            other => panic!("unexpected conversion: {:?}", other.ok()),
+        }
+        match ImageType::try_from(42) {
+            Ok(ImageType::Unknown(42)) => (),
+            other => panic!("unexpected conversion: {:?}", other.ok()),
+        }
+    }
+
+    #[test]
+    fn resizing_produces_the_dimensions_of_each_mode() {
+        let src = rgba(400, 200);
+        let resize = |mode| resize_image(&src, 100, 100, image::FilterType::Nearest, mode).dimensions();
+
+        assert_eq!(resize(ResizeMode::Fit), (100, 50));
+        assert_eq!(resize(ResizeMode::Fill), (100, 100));
+        assert_eq!(resize(ResizeMode::Crop), (100, 100));
+        assert_eq!(resize(ResizeMode::Stretch), (100, 100));
+        assert_eq!(resize(ResizeMode::Pad { background: image::Rgba([0, 0, 0, 0]) }), (100, 100));
+    }
+
+    #[test]
+    fn cropping_a_box_larger_than_the_image_keeps_the_image_size() {
+        let src = rgba(40, 20);
+        let cropped = resize_image(&src, 100, 100, image::FilterType::Nearest, ResizeMode::Crop);
+        assert_eq!(cropped.dimensions(), (40, 20));
+    }
+
+    /// Busy enough that encoder settings make a difference
+    fn pattern(w: u32, h: u32) -> DynamicImage {
+        DynamicImage::ImageRgba8(image::ImageBuffer::from_fn(w, h, |x, y| {
+            image::Rgba([(x ^ y) as u8, (x * 3) as u8, (y * 7) as u8, 255])
+        }))
+    }
+
+    #[test]
+    fn jpeg_quality_takes_effect() {
+        let img = DynamicImage::ImageRgb8(pattern(128, 128).to_rgb());
+        let low = encode(&img, image::JPEG, EncodeOptions::new().with_jpeg_quality(10)).unwrap();
+        let high = encode(&img, image::JPEG, EncodeOptions::new().with_jpeg_quality(95)).unwrap();
+        assert!(low.len() < high.len());
+        assert!(encode(&img, image::JPEG, EncodeOptions::new().with_jpeg_quality(0)).is_err());
+    }
+
+    #[test]
+    fn png_compression_takes_effect() {
+        let img = pattern(128, 128);
+        let fast = encode(&img, image::PNG, EncodeOptions::new().with_png_compression(PngCompression::Fast)).unwrap();
+        let best = encode(&img, image::PNG, EncodeOptions::new().with_png_compression(PngCompression::Best)).unwrap();
+        assert!(best.len() < fast.len());
+    }
+
+    #[test]
+    fn encoded_pngs_round_trip_for_every_color_type_and_filter() {
+        let rgba = pattern(23, 17).to_rgba();
+        let images = vec![
+            DynamicImage::ImageLuma8(image::ImageBuffer::from_fn(23, 17, |x, y| image::Luma([rgba.get_pixel(x, y)[0]]))),
+            DynamicImage::ImageLumaA8(image::ImageBuffer::from_fn(23, 17, |x, y| {
+                let p = rgba.get_pixel(x, y);
+                image::LumaA([p[0], p[1]])
+            })),
+            DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(rgba.clone()).to_rgb()),
+            DynamicImage::ImageRgba8(image::ImageBuffer::from_fn(23, 17, |x, y| {
+                let p = rgba.get_pixel(x, y);
+                image::Rgba([p[0], p[1], p[2], (x * 11) as u8])
+            })),
+        ];
+        let filters = [PngFilter::None, PngFilter::Sub, PngFilter::Up, PngFilter::Average, PngFilter::Paeth,
+                       PngFilter::Adaptive];
+
+        for img in &images {
+            for &compression in &[PngCompression::Fast, PngCompression::Default, PngCompression::Best] {
+                for &filter in &filters {
+                    let bytes = encode_png(img, compression, filter).unwrap();
+                    let decoded = image::load_from_memory_with_format(&bytes, image::PNG).unwrap();
+                    assert_eq!(decoded.color(), img.color(), "{:?} {:?}", compression, filter);
+                    assert_eq!(decoded.dimensions(), img.dimensions());
+                    assert!(decoded.raw_pixels() == img.raw_pixels(), "{:?} {:?} {:?}",
+                            img.color(), compression, filter);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn crc32_matches_the_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn unknown_formats_and_types_round_trip() {
+        let format = ImageFormat::from_i32(17).unwrap();
+        assert_eq!(format, ImageFormat::Unknown(17));
+        assert_eq!(format.as_i32(), 17);
+        assert_eq!(ImageFormat::from_i32(format.as_i32()).unwrap(), format);
+
+        let typ = ImageType::from_i32(9).unwrap();
+        assert_eq!(typ, ImageType::Unknown(9));
+        assert_eq!(ImageType::from_i32(typ.as_i32()).unwrap(), typ);
+        assert!(typ.store().is_err());
+    }
+
+    #[test]
+    fn images_of_unknown_formats_load_but_do_not_resize() {
+        let mut new = NewImage::new(ImageType::Local, "future.bin");
+        new.format = 17;
+        let img = Image::from_parts(1, new);
+        assert_eq!(img.get_format().unwrap(), ImageFormat::Unknown(17));
+        assert!(img.resizable_format().is_err());
+    }
+
+    #[test]
+    fn padding_letterboxes_with_the_background() {
+        let red = image::Rgba([255, 0, 0, 255]);
+        let blue = image::Rgba([0, 0, 255, 255]);
+        let src = DynamicImage::ImageRgba8(image::ImageBuffer::from_pixel(400, 300, red));
+        let padded = resize_image(&src, 100, 100, image::FilterType::Nearest, ResizeMode::Pad { background: blue });
+
+        // Scaled to 100x75, leaving 12 rows above and 13 below
+        assert_eq!(padded.dimensions(), (100, 100));
+        for &x in &[0, 50, 99] {
+            assert_eq!(padded.get_pixel(x, 0), blue);
+            assert_eq!(padded.get_pixel(x, 11), blue);
+            assert_eq!(padded.get_pixel(x, 12), red);
+            assert_eq!(padded.get_pixel(x, 86), red);
+            assert_eq!(padded.get_pixel(x, 87), blue);
+            assert_eq!(padded.get_pixel(x, 99), blue);
+        }
+    }
+
+    #[test]
+    fn padding_background_is_stored_with_the_mode() {
+        let mode = ResizeMode::Pad { background: image::Rgba([0x12, 0x34, 0x56, 0x78]) };
+        assert_eq!(ResizeMode::from_parts(mode.as_i32(), mode.background_i32()).unwrap(), mode);
+        let clear = ResizeMode::Pad { background: image::Rgba([0xff, 0, 0, 0]) };
+        assert_eq!(ResizeMode::from_parts(clear.as_i32(), clear.background_i32()).unwrap(), clear);
+    }
+
+    #[test]
+    fn local_images_are_served_from_their_path() {
+        let img = Image::from_parts(1, NewImage::new(ImageType::Local, "/assets/uploads/abc.png"));
+        assert_eq!(img.get_path().unwrap(), "/assets/uploads/abc.png");
+
+        let escaping = Image::from_parts(2, NewImage::new(ImageType::Local, "/assets/uploads/../../secret"));
+        assert!(escaping.get_path().is_err());
+    }
+
+    #[test]
+    fn inline_images_are_served_as_data_uris() {
+        let mut new = NewImage::new(ImageType::Base64, "iVBOR-w0_KGgo");
+        new.format = ImageFormat::PNG.as_i32();
+        let img = Image::from_parts(1, new);
+        assert_eq!(img.get_path().unwrap(), "data:image/png;base64,iVBOR+w0/KGgo");
+    }
+
+    #[test]
+    fn derivative_paths_resolve_into_the_derivative_dir() {
+        let dir = PathBuf::from("/var/cache/derivatives");
+        let (full, base) = stored_file_in("/assets/derivatives/64_64-abc.png", Some(&dir));
+        assert_eq!(full, dir.join("64_64-abc.png"));
+        assert_eq!(base, dir);
+
+        // Without a derivative dir they are plain upload paths, which this one isn't in
+        let (full, base) = stored_file_in("/assets/derivatives/64_64-abc.png", None);
+        assert_eq!(full, local_path("/assets/derivatives/64_64-abc.png"));
+        assert_eq!(base, upload_dir());
+
+        let (full, _) = stored_file_in("/assets/uploads/64_64-abc.png", Some(&dir));
+        assert_eq!(full, local_path("/assets/uploads/64_64-abc.png"));
+    }
+
+    /// A 1x1 grayscale, uncompressed TIFF
+    static TIFF_PIXEL : &'static [u8] = &[
+        0x49, 0x49, 0x2a, 0x00, 0x08, 0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x01,
+        0x03, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x01,
+        0x03, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02, 0x01,
+        0x03, 0x00, 0x01, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x03, 0x01,
+        0x03, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x06, 0x01,
+        0x03, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x11, 0x01,
+        0x04, 0x00, 0x01, 0x00, 0x00, 0x00, 0x7a, 0x00, 0x00, 0x00, 0x15, 0x01,
+        0x03, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x16, 0x01,
+        0x03, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x17, 0x01,
+        0x04, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x80,
+    ];
+
+    #[test]
+    fn tiff_uploads_are_accepted_and_stored_as_png() {
+        let fmt = guess_allowed_format(TIFF_PIXEL).unwrap();
+        assert_eq!(fmt, image::TIFF);
+        let img = image::load_from_memory_with_format(TIFF_PIXEL, fmt).unwrap();
+        assert_eq!(img.dimensions(), (1, 1));
+        assert_eq!(storage_format(fmt), image::PNG);
+        assert_eq!(ImageFormat::from_image_format(storage_format(fmt)).unwrap(), ImageFormat::PNG);
+    }
+
+    #[test]
+    fn upload_formats_parse_input_only_names() {
+        assert_eq!("tiff".parse::<UploadFormat>().unwrap(), UploadFormat::TIFF);
+        assert_eq!("TIF".parse::<UploadFormat>().unwrap(), UploadFormat::TIFF);
+        assert_eq!("jpeg".parse::<UploadFormat>().unwrap(), UploadFormat::Stored(ImageFormat::JPEG));
+        assert!("psd".parse::<UploadFormat>().is_err());
+        assert_eq!(UploadFormat::from_image_format(image::TIFF).unwrap().to_string(), "tiff");
+    }
+
+    fn sized(width: i32, height: i32) -> Image {
+        let mut new = NewImage::new(ImageType::Local, "/assets/uploads/sized.png");
+        new.width = width;
+        new.height = height;
+        Image::from_parts(1, new)
+    }
+
+    #[test]
+    fn aspect_ratio_is_width_over_height() {
+        assert_eq!(sized(1600, 900).aspect_ratio(), 1600.0 / 900.0);
+        assert_eq!(sized(300, 600).aspect_ratio(), 0.5);
+        assert_eq!(sized(0, 0).aspect_ratio(), 1.0);
+        assert_eq!(sized(100, 0).aspect_ratio(), 1.0);
+    }
+
+    #[test]
+    fn orientation_follows_the_longer_side() {
+        assert_eq!(sized(1600, 900).orientation(), Orientation::Landscape);
+        assert_eq!(sized(300, 600).orientation(), Orientation::Portrait);
+        assert_eq!(sized(512, 512).orientation(), Orientation::Square);
+    }
+
+    /// An empty directory of its own for each test
+    fn scratch_dir(name: &str) -> PathBuf {
+        use std::{env, fs};
+        let dir = env::temp_dir().join(format!("furry_cafe-test-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn atomic_writes_leave_only_the_final_file() {
+        use std::fs;
+        let dir = scratch_dir("atomic-ok");
+        let full = write_atomically(&dir, "a.png", b"pixels").unwrap();
+
+        assert_eq!(full, dir.join("a.png"));
+        let mut written = Vec::new();
+        File::open(&full).unwrap().read_to_end(&mut written).unwrap();
+        assert_eq!(written, b"pixels");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn interrupted_atomic_writes_leave_nothing_behind() {
+        use std::fs;
+        let dir = scratch_dir("atomic-fail");
+        // Renaming onto a directory that isn't empty fails after the temporary file was completely written
+        fs::create_dir_all(dir.join("a.png").join("in-the-way")).unwrap();
+
+        assert!(write_atomically(&dir, "a.png", b"pixels").is_err());
+        assert!(dir.join("a.png").is_dir());
+        assert!(!dir.join(".a.png.tmp").exists());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn stored(bytes: &[u8], format: ImageFormat) -> Image {
+        let mut new = NewImage::new(ImageType::Local, "/assets/uploads/stored.png");
+        new.format = format.as_i32();
+        new.file_hash = Some(bytes_hash(bytes));
+        Image::from_parts(1, new)
+    }
+
+    #[test]
+    fn different_images_get_different_hashed_filenames() {
+        let png = encode(&pattern(8, 8), image::PNG, EncodeOptions::new()).unwrap();
+        let other = encode(&pattern(8, 9), image::PNG, EncodeOptions::new()).unwrap();
+        let a = stored(&png, ImageFormat::PNG).hashed_filename().unwrap();
+        let b = stored(&other, ImageFormat::PNG).hashed_filename().unwrap();
+        assert!(a != b);
+        assert!(a.ends_with(".png"));
+        assert_eq!(stored(&png, ImageFormat::PNG).hashed_filename().unwrap(), a);
+    }
+
+    #[test]
+    fn reencoding_changes_the_hashed_filename() {
+        let img = pattern(32, 32);
+        let fast = encode(&img, image::PNG, EncodeOptions::new().with_png_compression(PngCompression::Fast)).unwrap();
+        let best = encode(&img, image::PNG, EncodeOptions::new().with_png_compression(PngCompression::Best)).unwrap();
+        assert!(stored(&fast, ImageFormat::PNG).hashed_filename() != stored(&best, ImageFormat::PNG).hashed_filename());
+        assert!(NewImage::new(ImageType::Local, "old.png").file_hash.is_none());
+    }
+
+    #[test]
+    fn thin_images_keep_at_least_one_pixel() {
+        assert_eq!(fit_dimensions(1000, 1, 100, 100), (100, 1));
+        assert_eq!(fit_dimensions(1, 1000, 100, 100), (1, 100));
+        assert_eq!(fit_dimensions(10000, 3, 64, 64), (64, 1));
+
+        let fitted = resize_image(&rgba(1000, 1), 100, 100, image::FilterType::Nearest, ResizeMode::Fit);
+        assert_eq!(fitted.dimensions(), (100, 1));
+    }
+
+    #[test]
+    fn fitting_keeps_the_aspect_ratio() {
+        assert_eq!(fit_dimensions(400, 300, 100, 100), (100, 75));
+        assert_eq!(fit_dimensions(300, 400, 100, 100), (75, 100));
+        assert_eq!(fit_dimensions(50, 50, 100, 200), (100, 100));
+    }
+
+    static PIXEL_PNG_BASE64 : &'static str =
+        "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR4nGP4z8DwHwAFAAH/iZk9HQAAAABJRU5ErkJggg==";
+
+    #[test]
+    fn data_uris_are_stored_inline() {
+        let new = NewImage::from_data_uri(&format!("data:image/png;base64,{}", PIXEL_PNG_BASE64)).unwrap();
+        assert_eq!(new.host_type, ImageType::Base64.as_i32());
+        assert_eq!(new.format, ImageFormat::PNG.as_i32());
+        assert_eq!((new.width, new.height), (1, 1));
+    }
+
+    #[test]
+    fn images_can_be_read_from_a_stream() {
+        use std::io::Cursor;
+        let png = PIXEL_PNG_BASE64.from_base64().unwrap();
+        let new = NewImage::from_reader(Cursor::new(png), EncodeOptions::new()).unwrap();
+        assert_eq!(new.host_type, ImageType::Base64.as_i32());
+        assert_eq!(new.format, ImageFormat::PNG.as_i32());
+        assert_eq!((new.width, new.height), (1, 1));
+    }
+
+    #[test]
+    fn malformed_data_uris_are_rejected() {
+        let uris = [
+            PIXEL_PNG_BASE64.to_owned(),
+            format!("image/png;base64,{}", PIXEL_PNG_BASE64),
+            "data:image/png;base64".to_owned(),
+            format!("data:image/png,{}", PIXEL_PNG_BASE64),
+            "data:image/png;base64,not*base64!".to_owned(),
+        ];
+        for uri in &uris {
+            match NewImage::from_data_uri(uri) {
+                Err(error::FurryError::InvalidDataUri) => (),
+                Err(e) => panic!("{} failed with {}", uri, e),
+                Ok(_) => panic!("{} was accepted", uri),
+            }
+        }
+    }
+
+    #[test]
+    fn inline_jpegs_are_served_as_jpeg_data_uris() {
+        let img = DynamicImage::ImageRgb8(pattern(16, 16).to_rgb());
+        let new = NewImage::create_from_dynamic_image(&img, "test", image::JPEG).unwrap();
+        assert_eq!(new.host_type, ImageType::Base64.as_i32());
+
+        let stored = Image::from_parts(1, new);
+        assert!(stored.get_path().unwrap().starts_with("data:image/jpeg;base64,"));
+    }
+
+    #[test]
+    fn inline_images_decode_back_to_their_declared_format() {
+        for &fmt in &[image::PNG, image::JPEG, image::GIF] {
+            let img = DynamicImage::ImageRgb8(pattern(16, 16).to_rgb());
+            let stored = Image::from_parts(1, NewImage::create_from_dynamic_image(&img, "test", fmt).unwrap());
+            let bytes = stored.bytes().unwrap();
+            assert_eq!(image::guess_format(&bytes).unwrap(), fmt);
+            assert_eq!(stored.get_format().unwrap().as_image_format().unwrap(), fmt);
+            assert_eq!(image::load_from_memory_with_format(&bytes, fmt).unwrap().dimensions(), (16, 16));
+        }
+    }
+
+    /// A stored original large enough to not be inlined, whose derivatives exist for real
+    #[cfg(feature = "db-tests")]
+    fn stored_original() -> Image {
+        use std::fs;
+        fs::create_dir_all(upload_dir()).unwrap();
+        let img = DynamicImage::ImageRgb8(pattern(300, 200).to_rgb());
+        let new = NewImage::create_from_dynamic_image_with_threshold(&img, "test", image::PNG, None).unwrap();
+        find(Image::create_from(new).unwrap()).unwrap().unwrap()
+    }
+
+    #[cfg(feature = "db-tests")]
+    #[test]
+    fn deleting_with_children_takes_every_generation_along() {
+        let parent = stored_original();
+        let small = parent.get_with_size(100, 100).unwrap();
+        let rotated = parent.rotated(Rotation::R90).unwrap();
+        let grandchild = rotated.get_with_size(50, 50).unwrap();
+        let all = [&parent, &small, &rotated, &grandchild];
+        // Small derivatives are inlined and have no file
+        let files = all.iter().filter(|i| i.host_type == ImageType::Local.as_i32())
+            .map(|i| resolve_local(&i.path).unwrap()).collect::<Vec<_>>();
+        assert!(files.len() >= 2);
+        assert!(files.iter().all(|f| f.exists()));
+
+        assert_eq!(parent.delete_with_children().unwrap(), 4);
+        for i in &all {
+            assert!(find_include_deleted(i.id).unwrap().is_none());
+        }
+        assert!(files.iter().all(|f| !f.exists()));
+    }
+
+    #[test]
+    fn oversized_images_are_refused() {
+        match NewImage::create_from_dynamic_image(&rgba(10001, 1), "test", image::PNG) {
+            Err(error::FurryError::ImageTooLarge { width: 10001, height: 1, max: 10000 }) => (),
+            Err(e) => panic!("failed with {}", e),
+            Ok(_) => panic!("oversized image was stored"),
+        }
+
+        let mut new = NewImage::new(ImageType::Local, "/assets/uploads/huge.png");
+        new.width = 200;
+        new.height = 20000;
+        match NewImage::create_from_image_with_size(&Image::from_parts(1, new), 100, 100) {
+            Err(error::FurryError::ImageTooLarge { width: 200, height: 20000, max: 10000 }) => (),
+            Err(e) => panic!("failed with {}", e),
+            Ok(_) => panic!("oversized image was resized"),
+        }
+    }
+
+    /// An IFD entry as it is written, tag, type, count and the value in big endian
+    type ExifEntry = (u16, u16, u32, Vec<u8>);
+
+    fn push_be(buf: &mut Vec<u8>, v: u32, bytes: usize) {
+        for i in (0..bytes).rev() {
+            buf.push((v >> (8 * i)) as u8);
+        }
+    }
+
+    fn short_entry(tag: u16, v: u16) -> ExifEntry {
+        (tag, 3, 1, vec![(v >> 8) as u8, v as u8])
+    }
+
+    fn ascii_entry(tag: u16, s: &str) -> ExifEntry {
+        let mut value = s.as_bytes().to_vec();
+        value.push(0);
+        (tag, 2, value.len() as u32, value)
+    }
+
+    /// Bytes taken up by an IFD and the values that don't fit into its entries
+    fn ifd_len(entries: &[ExifEntry]) -> usize {
+        6 + 12 * entries.len() + entries.iter().filter(|e| e.3.len() > 4).map(|e| (e.3.len() + 1) / 2 * 2).sum::<usize>()
+    }
+
+    fn push_ifd(tiff: &mut Vec<u8>, entries: &[ExifEntry]) {
+        let data_at = tiff.len() + ifd_len(&[]) + 12 * entries.len();
+        let mut data = Vec::new();
+        push_be(tiff, entries.len() as u32, 2);
+        for &(tag, typ, count, ref value) in entries {
+            push_be(tiff, tag as u32, 2);
+            push_be(tiff, typ as u32, 2);
+            push_be(tiff, count, 4);
+            if value.len() <= 4 {
+                let mut inline = value.clone();
+                inline.resize(4, 0);
+                tiff.extend_from_slice(&inline);
+            } else {
+                push_be(tiff, (data_at + data.len()) as u32, 4);
+                data.extend_from_slice(value);
+                if data.len() % 2 == 1 {
+                    data.push(0);
+                }
+            }
+        }
+        push_be(tiff, 0, 4);
+        tiff.extend_from_slice(&data);
+    }
+
+    /// A `width`x`height` JPEG with an EXIF block holding `ifd0`, `exif` and `gps` get IFDs of their own unless empty
+    fn jpeg_with_exif(width: u32, height: u32, mut ifd0: Vec<ExifEntry>, exif: Vec<ExifEntry>, gps: Vec<ExifEntry>)
+        -> Vec<u8>
+    {
+        fn offset(at: usize) -> Vec<u8> {
+            let mut value = Vec::new();
+            push_be(&mut value, at as u32, 4);
+            value
+        }
+
+        // The pointers to the other IFDs fit into their entries, so the first IFD's size is known up front
+        let pointers = (!exif.is_empty()) as usize + (!gps.is_empty()) as usize;
+        let exif_at = 8 + ifd_len(&ifd0) + 12 * pointers;
+        let gps_at = exif_at + if exif.is_empty() { 0 } else { ifd_len(&exif) };
+        if !exif.is_empty() {
+            ifd0.push((0x8769, 4, 1, offset(exif_at)));
+        }
+        if !gps.is_empty() {
+            ifd0.push((0x8825, 4, 1, offset(gps_at)));
+        }
+
+        let mut tiff = b"MM\0\x2a\0\0\0\x08".to_vec();
+        push_ifd(&mut tiff, &ifd0);
+        if !exif.is_empty() {
+            push_ifd(&mut tiff, &exif);
+        }
+        if !gps.is_empty() {
+            push_ifd(&mut tiff, &gps);
+        }
+
+        let img = DynamicImage::ImageRgb8(pattern(width, height).to_rgb());
+        let jpeg = encode(&img, image::JPEG, EncodeOptions::new()).unwrap();
+        let mut out = jpeg[..2].to_vec();
+        push_be(&mut out, 0xffe1, 2);
+        push_be(&mut out, (2 + 6 + tiff.len()) as u32, 2);
+        out.extend_from_slice(b"Exif\0\0");
+        out.extend_from_slice(&tiff);
+        out.extend_from_slice(&jpeg[2..]);
+        out
+    }
+
+    #[test]
+    fn sideways_jpegs_are_turned_upright() {
+        let bytes = jpeg_with_exif(40, 20, vec![short_entry(0x0112, 6)], vec![], vec![]);
+        let img = image::load_from_memory_with_format(&bytes, image::JPEG).unwrap();
+        assert_eq!(img.dimensions(), (40, 20));
+        assert_eq!(orient(img, &bytes).dimensions(), (20, 40));
+
+        let upright = jpeg_with_exif(40, 20, vec![short_entry(0x0112, 1)], vec![], vec![]);
+        let img = image::load_from_memory_with_format(&upright, image::JPEG).unwrap();
+        assert_eq!(orient(img, &upright).dimensions(), (40, 20));
+    }
+
+    /// The markers of the segments before the entropy coded data
+    fn jpeg_markers(bytes: &[u8]) -> Vec<u8> {
+        let mut markers = Vec::new();
+        let mut pos = 2;
+        while pos + 3 < bytes.len() && bytes[pos + 1] != 0xDA {
+            markers.push(bytes[pos + 1]);
+            pos += 2 + ((bytes[pos + 2] as usize) << 8 | bytes[pos + 3] as usize);
+        }
+        markers
+    }
+
+    fn png_chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = Vec::new();
+        push_be(&mut chunk, data.len() as u32, 4);
+        chunk.extend_from_slice(kind);
+        chunk.extend_from_slice(data);
+        let crc = crc32(&chunk[4..]);
+        push_be(&mut chunk, crc, 4);
+        chunk
+    }
+
+    #[test]
+    fn jpeg_metadata_is_stripped() {
+        use std::io::Cursor;
+        use exif::{Reader, Tag};
+
+        fn rational(v: u32) -> Vec<u8> {
+            let mut value = Vec::new();
+            push_be(&mut value, v, 4);
+            push_be(&mut value, 1, 4);
+            value
+        }
+
+        let latitude = [rational(52), rational(31), rational(0)].concat();
+        let gps = vec![ascii_entry(0x0001, "N"), (0x0002, 5, 3, latitude)];
+        let bytes = jpeg_with_exif(16, 16, vec![], vec![], gps);
+        let reader = Reader::new(&mut Cursor::new(&bytes[..])).unwrap();
+        assert!(reader.get_field(Tag::GPSLatitude, false).is_some());
+
+        let stripped = strip_jpeg_metadata(&bytes).unwrap();
+        assert!(jpeg_markers(&bytes).contains(&0xE1));
+        assert!(!jpeg_markers(&stripped).contains(&0xE1));
+        assert!(Reader::new(&mut Cursor::new(&stripped[..])).is_err());
+        assert_eq!(image::load_from_memory_with_format(&stripped, image::JPEG).unwrap().dimensions(), (16, 16));
+
+        // Uploads never keep it either
+        let stored = Image::from_parts(1, NewImage::from_bytes(&bytes, ImageFormat::JPEG, "test").unwrap());
+        assert!(!jpeg_markers(&stored.bytes().unwrap()).contains(&0xE1));
+    }
+
+    #[test]
+    fn png_metadata_is_stripped() {
+        let png = encode_png(&pattern(8, 8), PngCompression::Default, PngFilter::None).unwrap();
+        // Right after the signature and IHDR
+        let header_end = 8 + 12 + 13;
+        let mut tagged = png[..header_end].to_vec();
+        tagged.extend(png_chunk(b"tEXt", b"Comment\0taken at home"));
+        tagged.extend(png_chunk(b"eXIf", b"MM\0\x2a\0\0\0\x08\0\0\0\0\0\0"));
+        tagged.extend(png_chunk(b"tIME", &[0x07, 0xe1, 3, 1, 12, 34, 56]));
+        tagged.extend_from_slice(&png[header_end..]);
+        assert!(image::load_from_memory_with_format(&tagged, image::PNG).is_ok());
+
+        let stripped = strip_png_metadata(&tagged).unwrap();
+        assert_eq!(stripped, png);
+        assert_eq!(image::load_from_memory_with_format(&stripped, image::PNG).unwrap().dimensions(), (8, 8));
+    }
+
+    #[test]
+    fn sizes_that_are_not_positive_are_refused() {
+        let img = sized(300, 200);
+        for &(w, h) in &[(0, 100), (100, 0), (-5, 100)] {
+            match NewImage::create_from_image_with_size(&img, w, h) {
+                Err(error::FurryError::InvalidDimensions { width, height }) => assert_eq!((width, height), (w, h)),
+                Err(e) => panic!("{}x{} failed with {}", w, h, e),
+                Ok(_) => panic!("{}x{} was resized", w, h),
+            }
+        }
+    }
+
+    /// Writes `bytes` into the upload directory, returning the stored path and the file
+    fn upload_file(name: &str, bytes: &[u8]) -> (String, PathBuf) {
+        use std::fs;
+        fs::create_dir_all(upload_dir()).unwrap();
+        let path = format!("{}/furry_cafe-test-{}", UPLOAD_PATH, name);
+        let full = local_path(&path);
+        File::create(&full).unwrap().write_all(bytes).unwrap();
+        (path, full)
+    }
+
+    #[test]
+    fn local_files_get_their_dimensions_and_format() {
+        use std::fs;
+        let png = encode(&pattern(30, 20), image::PNG, EncodeOptions::new()).unwrap();
+        let (path, full) = upload_file("plain.png", &png);
+
+        let new = NewImage::from_local_path(&path).unwrap();
+        assert_eq!((new.width, new.height), (30, 20));
+        assert_eq!(new.format, ImageFormat::PNG.as_i32());
+        assert_eq!(new.byte_size, png.len() as i64);
+
+        // Nothing to strip, the file stays untouched
+        let mut kept = Vec::new();
+        File::open(&full).unwrap().read_to_end(&mut kept).unwrap();
+        assert_eq!(kept, png);
+        fs::remove_file(&full).unwrap();
+    }
+
+    #[test]
+    fn local_files_with_metadata_are_rewritten_upright() {
+        use std::fs;
+        let gps = vec![ascii_entry(0x0001, "N")];
+        let exif = vec![ascii_entry(0x9003, "2017:03:01 12:34:56")];
+        let jpeg = jpeg_with_exif(40, 20, vec![short_entry(0x0112, 6)], exif, gps);
+        let (path, full) = upload_file("sideways.jpg", &jpeg);
+
+        let new = NewImage::from_local_path(&path).unwrap();
+        assert_eq!((new.width, new.height), (20, 40));
+        assert!(new.captured_at.is_some());
+
+        let mut rewritten = Vec::new();
+        File::open(&full).unwrap().read_to_end(&mut rewritten).unwrap();
+        assert_eq!(new.byte_size, rewritten.len() as i64);
+        assert!(!jpeg_markers(&rewritten).contains(&0xE1));
+        assert_eq!(image::load_from_memory_with_format(&rewritten, image::JPEG).unwrap().dimensions(), (20, 40));
+        assert!(!full.with_file_name(".furry_cafe-test-sideways.jpg.tmp").exists());
+        fs::remove_file(&full).unwrap();
+    }
+
+    /// A `width`x`height` GIF with a frame in each of `colors`
+    fn animated_gif(width: u16, height: u16, colors: &[[u8; 4]]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut buf, width, height, &[]).unwrap();
+            for color in colors {
+                let mut pixels = color.iter().cloned().cycle().take(width as usize * height as usize * 4)
+                    .collect::<Vec<u8>>();
+                let mut frame = gif::Frame::from_rgba(width, height, &mut pixels);
+                frame.delay = 10;
+                encoder.write_frame(&frame).unwrap();
+            }
+        }
+        buf
+    }
+
+    /// The frames of a GIF as they are, the test GIFs have no frames that only cover part of the canvas
+    fn gif_frames(bytes: &[u8]) -> Vec<gif::Frame<'static>> {
+        use gif::SetParameter;
+        let mut decoder = gif::Decoder::new(bytes);
+        decoder.set(gif::ColorOutput::RGBA);
+        let mut reader = decoder.read_info().unwrap();
+        let mut frames = Vec::new();
+        while let Some(frame) = reader.read_next_frame().unwrap() {
+            frames.push(frame.clone());
+        }
+        frames
+    }
+
+    #[test]
+    fn animated_gifs_keep_every_frame_when_resized() {
+        let colors = [[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 255]];
+        let bytes = animated_gif(40, 20, &colors);
+        assert!(is_animated_gif(&bytes).unwrap());
+
+        let (resized, dims, _) = resize_animated_gif(&bytes, 20, 20, ResizeOptions::new()).unwrap();
+        assert_eq!(dims, (20, 10));
+        let frames = gif_frames(&resized);
+        assert_eq!(frames.len(), 3);
+        for (frame, color) in frames.iter().zip(&colors) {
+            assert_eq!((frame.width, frame.height), (20, 10));
+            // The palette gets quantized, so colors only come out close to what went in
+            for c in 0..3 {
+                let diff = (frame.buffer[c] as i32 - color[c] as i32).abs();
+                assert!(diff < 16, "channel {} is off by {}", c, diff);
+            }
+            assert_eq!(frame.delay, 10);
+        }
+    }
+
+    #[test]
+    fn animated_gif_uploads_are_stored_as_they_are() {
+        let bytes = animated_gif(8, 8, &[[255, 0, 0, 255], [0, 0, 255, 255], [0, 255, 0, 255]]);
+        let stored = Image::from_parts(1, NewImage::from_bytes(&bytes, ImageFormat::GIF, "test").unwrap());
+        assert_eq!(stored.get_format().unwrap(), ImageFormat::GIF);
+        assert_eq!(stored.bytes().unwrap(), bytes);
+        assert_eq!(gif_frames(&stored.bytes().unwrap()).len(), 3);
+    }
+
+    #[test]
+    fn truncated_inline_data_is_reported_with_its_image() {
+        let mut new = NewImage::new(ImageType::Base64, &PIXEL_PNG_BASE64[..9]);
+        new.format = ImageFormat::PNG.as_i32();
+        match Image::from_parts(42, new).bytes() {
+            Err(error::FurryError::CorruptBase64 { image_id: 42, .. }) => (),
+            Err(e) => panic!("failed with {}", e),
+            Ok(_) => panic!("truncated data was decoded"),
+        }
+    }
+
+    #[test]
+    fn accent_color_of_a_solid_image_is_that_color() {
+        let red = DynamicImage::ImageRgba8(image::ImageBuffer::from_pixel(20, 10, image::Rgba([255, 0, 0, 255])));
+        assert_eq!(accent_color(&red), 0xff0000);
+
+        // Fully transparent pixels don't pull the color towards whatever they hold
+        let half = DynamicImage::ImageRgba8(image::ImageBuffer::from_fn(32, 32, |x, _| {
+            if x < 16 { image::Rgba([0, 0, 255, 255]) } else { image::Rgba([255, 255, 255, 0]) }
+        }));
+        // Only the pixels along the edge get blended when scaling down
+        let color = accent_color(&half);
+        assert!((color >> 16) & 0xff < 64 && (color >> 8) & 0xff < 64 && color & 0xff > 200, "got {:06x}", color);
+    }
+
+    #[test]
+    fn blurhashes_start_with_their_component_count() {
+        let hash = blurhash(&pattern(64, 48));
+        // 4x3 components: one size flag, one max value, four for the DC and two for each of the 11 AC terms
+        assert_eq!(hash.len(), 1 + 1 + 4 + 2 * 11);
+        assert!(hash.starts_with('L'), "got {}", hash);
+
+        let red = DynamicImage::ImageRgba8(image::ImageBuffer::from_pixel(16, 16, image::Rgba([255, 0, 0, 255])));
+        let flat = blurhash(&red);
+        assert_eq!(flat.len(), hash.len());
+        assert_ne!(flat, hash);
+    }
+
+    #[test]
+    fn avif_images_fall_back_to_a_format_that_can_be_encoded() {
+        let format = encodable_format(ImageFormat::AVIF).unwrap();
+        assert!(format.can_encode());
+        assert_eq!(format, *AVIF_FALLBACK);
+
+        let new = NewImage::create_from_dynamic_image_as(&pattern(16, 16), "test", ImageFormat::AVIF).unwrap();
+        let stored = Image::from_parts(1, new);
+        assert_eq!(stored.get_format().unwrap(), format);
+        let decoded = image::load_from_memory_with_format(&stored.bytes().unwrap(), format.as_image_format().unwrap());
+        assert_eq!(decoded.unwrap().dimensions(), (16, 16));
+    }
+
+    #[test]
+    fn large_opaque_pngs_are_stored_as_jpeg() {
+        let opaque = DynamicImage::ImageRgb8(pattern(64, 64).to_rgb());
+        let new = NewImage::create_from_dynamic_image_with(&opaque, "test", image::PNG, *INLINE_MAX_DIM,
+                                                           EncodeOptions::new(), Some(100)).unwrap();
+        assert_eq!(Image::from_parts(1, new).get_format().unwrap(), ImageFormat::JPEG);
+
+        // JPEG would lose the transparency
+        let mut translucent = pattern(64, 64).to_rgba();
+        translucent.put_pixel(0, 0, image::Rgba([0, 0, 0, 0]));
+        let new = NewImage::create_from_dynamic_image_with(&DynamicImage::ImageRgba8(translucent), "test", image::PNG,
+                                                           *INLINE_MAX_DIM, EncodeOptions::new(), Some(100)).unwrap();
+        assert_eq!(Image::from_parts(1, new).get_format().unwrap(), ImageFormat::PNG);
+
+        // Small enough already
+        let new = NewImage::create_from_dynamic_image_with(&opaque, "test", image::PNG, *INLINE_MAX_DIM,
+                                                           EncodeOptions::new(), Some(INLINE_MAX_BYTES)).unwrap();
+        let stored = Image::from_parts(1, new);
+        assert_eq!(stored.get_format().unwrap(), ImageFormat::PNG);
+        assert_eq!(image::load_from_memory_with_format(&stored.bytes().unwrap(), image::PNG).unwrap().to_rgb(),
+                   opaque.to_rgb());
+    }
+
+    #[test]
+    fn only_images_with_see_through_pixels_have_alpha() {
+        assert!(!has_alpha(&pattern(8, 8)));
+        assert!(!has_alpha(&DynamicImage::ImageRgb8(pattern(8, 8).to_rgb())));
+
+        let mut rgba = pattern(8, 8).to_rgba();
+        rgba.put_pixel(3, 5, image::Rgba([10, 20, 30, 254]));
+        assert!(has_alpha(&DynamicImage::ImageRgba8(rgba)));
+
+        let luma = DynamicImage::ImageLumaA8(image::ImageBuffer::from_fn(8, 8, |x, _| image::LumaA([128, (x * 32) as u8])));
+        assert!(has_alpha(&luma));
+    }
+
+    #[test]
+    fn corrupt_image_data_is_a_decode_error() {
+        // Changing the width in the header without fixing up its checksum
+        let mut bytes = PIXEL_PNG_BASE64.from_base64().unwrap();
+        bytes[19] = 2;
+        match image::load_from_memory_with_format(&bytes, image::PNG).map_err(error::FurryError::from) {
+            Err(error::FurryError::Decode(_)) => (),
+            Err(e) => panic!("failed with {}", e),
+            Ok(_) => panic!("corrupt data was decoded"),
+        }
+    }
+
+    #[test]
+    fn previews_of_images_that_already_fit_are_the_image_itself() {
+        // Decoded images are cached by id, this one shouldn't pick up what another test left there
+        let img = Image::from_parts(70, NewImage::create_from_dynamic_image(&pattern(50, 50), "test", image::PNG).unwrap());
+        assert_eq!(img.preview_resize(100, 100).unwrap(), img.bytes().unwrap());
+
+        let smaller = image::load_from_memory_with_format(&img.preview_resize(25, 100).unwrap(), image::PNG).unwrap();
+        assert_eq!(smaller.dimensions(), (25, 25));
+    }
+
+    #[test]
+    fn flattening_blends_pixels_over_the_background() {
+        let img = DynamicImage::ImageRgba8(image::ImageBuffer::from_fn(3, 1, |x, _| match x {
+            0 => image::Rgba([255, 0, 0, 255]),
+            1 => image::Rgba([255, 0, 0, 128]),
+            _ => image::Rgba([0, 0, 255, 0]),
+        }));
+        let flat = flatten_alpha(&img, (255, 255, 255)).to_rgb();
+        assert_eq!(flat.get_pixel(0, 0).data, [255, 0, 0]);
+        assert_eq!(flat.get_pixel(1, 0).data, [255, 127, 127]);
+        assert_eq!(flat.get_pixel(2, 0).data, [255, 255, 255]);
+
+        // Which is what ends up in a JPEG made from a transparent PNG
+        let new = NewImage::create_from_dynamic_image_as(&img, "test", ImageFormat::JPEG).unwrap();
+        assert!(!new.has_alpha);
+    }
+
+    #[test]
+    fn gifs_declared_as_png_are_a_mismatch() {
+        let gif = animated_gif(4, 4, &[[255, 0, 0, 255]]);
+        match detect_format(&gif, ImageFormat::PNG) {
+            Ok(fmt) => assert!(*CORRECT_FORMAT_MISMATCH && fmt == image::GIF),
+            Err(error::FurryError::FormatMismatch { declared: ImageFormat::PNG, detected }) => {
+                assert!(!*CORRECT_FORMAT_MISMATCH);
+                assert_eq!(detected, UploadFormat::Stored(ImageFormat::GIF));
+            }
+            Err(e) => panic!("failed with {}", e),
+        }
+        assert!(detect_format(&gif, ImageFormat::GIF).unwrap() == image::GIF);
+    }
+
+    #[test]
+    fn capture_times_come_from_the_exif_data() {
+        use chrono::TimeZone;
+        let jpeg = jpeg_with_exif(16, 16, vec![], vec![ascii_entry(0x9003, "2017:03:01 12:34:56")], vec![]);
+        let at = captured_at(&jpeg).expect("no capture time found");
+        assert_eq!(helper::pg_timestamp_to_utc(&at), Utc.ymd(2017, 3, 1).and_hms(12, 34, 56));
+
+        let new = NewImage::from_bytes(&jpeg, ImageFormat::JPEG, "test").unwrap();
+        assert_eq!(new.captured_at.as_ref().map(helper::pg_timestamp_to_utc), Some(Utc.ymd(2017, 3, 1).and_hms(12, 34, 56)));
+
+        assert!(captured_at(&jpeg_with_exif(16, 16, vec![short_entry(0x0112, 1)], vec![], vec![])).is_none());
+        assert!(captured_at(&PIXEL_PNG_BASE64.from_base64().unwrap()).is_none());
+    }
+
+    #[test]
+    fn responsive_sets_with_formats_that_cant_be_encoded_are_refused() {
+        match sized(1024, 768).responsive_set(&[256, 512], &[ImageFormat::WEBP, ImageFormat::JPEG]) {
+            Err(error::FurryError::EncoderUnavailable("webp")) => (),
+            Err(e) => panic!("failed with {}", e),
+            Ok(set) => panic!("got a set of {} images", set.len()),
+        }
+    }
+
+    #[test]
+    fn blank_images_score_lowest() {
+        let gray = DynamicImage::ImageRgba8(image::ImageBuffer::from_pixel(64, 64, image::Rgba([128, 128, 128, 255])));
+        assert!(quality_score(&gray).abs() < 0.01);
+
+        let sharp = pattern(64, 64);
+        assert!(quality_score(&sharp) > quality_score(&sharp.blur(4.0)));
+        assert!(quality_score(&sharp.blur(4.0)) > quality_score(&gray));
+    }
+}
@@ -12,16 +12,25 @@ use database;
 use models;
 use error;
 
-fn convert_image(mut img: DynamicImage, fmt: image::ImageFormat) -> Option<i64> {
+/// Stores a submitted image, `animated` holds the uploaded bytes of animated GIFs which are kept as they are
+fn convert_image(mut img: DynamicImage, fmt: image::ImageFormat, owner: i64,
+                 captured_at: Option<diesel::data_types::PgTimestamp>, animated: Option<Vec<u8>>) -> Option<i64> {
     use image::FilterType;
-    match img.dimensions() {
-        (x, y) if x > 3000 || y > 3000 => {
-            img = img.resize(3000, 3000, FilterType::CatmullRom);
-        }
-        _ => (),
-    }
 
-    let new_image = match NewImage::create_from_dynamic_image(&img, "submission", fmt) {
+    let (x, y) = img.dimensions();
+    let too_large = x > 3000 || y > 3000;
+
+    let new_image = match animated {
+        // Resizing would take every frame through `resize_animated_gif`, which is not worth it for uploads
+        Some(ref bytes) if !too_large => NewImage::from_animated_gif(bytes, &img, "submission"),
+        _ => {
+            if too_large {
+                img = img.resize(3000, 3000, FilterType::CatmullRom);
+            }
+            NewImage::create_from_dynamic_image(&img, "submission", fmt)
+        }
+    };
+    let new_image = match new_image {
         Ok(t) => t,
         Err(e) => {
             error!("Could not create from dynamic image {}", e);
@@ -29,7 +38,7 @@ fn convert_image(mut img: DynamicImage, fmt: image::ImageFormat) -> Option<i64>
         }
     };
 
-    match Image::create_from(new_image) {
+    match Image::create_from(new_image.with_owner(owner).with_captured_at(captured_at)) {
         Ok(t) => Some(t),
         Err(e) => {
             error!("Could not save image {}", e);
@@ -245,11 +254,17 @@ impl<'a> UpdateSubmission<'a> {
                 if f.read_to_end(&mut buffer).is_err() {
                     se.image.push("Image is not in a valid format");
                 } else {
-                    to_be_converted = match image::guess_format(&buffer) {
-                        Ok(image::PNG) | Ok(image::JPEG) | Ok(image::GIF) => {
-                            match image::load_from_memory(&buffer) {
+                    to_be_converted = match models::image::guess_allowed_format(&buffer) {
+                        Ok(fmt) => {
+                            match image::load_from_memory_with_format(&buffer, fmt) {
                                 Ok(t) => {
-                                    Some((t, image::guess_format(&buffer).unwrap()))
+                                    // Resizing drops the EXIF data, so the date has to come from the upload
+                                    let animated = match models::image::is_animated_gif(&buffer) {
+                                        Ok(true) => Some(buffer.clone()),
+                                        _ => None,
+                                    };
+                                    Some((models::image::orient(t, &buffer), fmt,
+                                          models::image::captured_at(&buffer), animated))
                                 }
                                 Err(e) => {
                                     info!("Could not load image {}", e);
@@ -258,14 +273,9 @@ impl<'a> UpdateSubmission<'a> {
                                 }
                             }
                         }
-                        Ok(t) => {
-                            info!("Could not load image format {:?}", t);
-                            se.image.push("Image is not in a valid format, valid formats are: JPEG, PNG or GIF");
-                            None
-                        }
                         Err(t) => {
                             info!("Could not load image format {}", t);
-                            se.image.push("Image is not in a valid format, valid formats are: JPEG, PNG or GIF");
+                            se.image.push("Image is not in a format that is accepted here");
                             None
                         }
                     }
@@ -277,7 +287,7 @@ impl<'a> UpdateSubmission<'a> {
             se.image.push("Image cannot be empty");
         }
 
-        let image = to_be_converted.and_then(|(x, fmt)| convert_image(x, fmt));
+        let image = to_be_converted.and_then(|(x, fmt, at, animated)| convert_image(x, fmt, sub.user_id, at, animated));
 
         let us = UpdateSubmission {
             title: title,
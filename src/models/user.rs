@@ -172,7 +172,7 @@ pub struct UpdateUser<'a> {
 }
 
 impl<'a> UpdateUser<'a> {
-    pub fn new<'b>(name: Option<&'b str>, mut password: Option<&'b str>, file: Option<&File>)
+    pub fn new<'b>(owner: i64, name: Option<&'b str>, mut password: Option<&'b str>, file: Option<&File>)
         -> Result<UpdateUser<'b>, UserError>
 {
         let mut ue = UserError::new();
@@ -201,9 +201,11 @@ impl<'a> UpdateUser<'a> {
                     ue.profile_image.push("Image is not in a valid format");
 
                 } else {
-                    to_be_converted = match image::load_from_memory(&buffer) {
+                    to_be_converted = match models::image::guess_allowed_format(&buffer)
+                        .and_then(|fmt| image::load_from_memory_with_format(&buffer, fmt).map_err(|e| e.into()))
+                    {
                         Ok(t) => {
-                            Some(t)
+                            Some(models::image::orient(t, &buffer))
                         }
                         Err(e) => {
                             info!("Could not load image: {}", e);
@@ -240,7 +242,7 @@ impl<'a> UpdateUser<'a> {
                 }
             };
 
-            match Image::create_from(new_image) {
+            match Image::create_from(new_image.with_owner(owner)) {
                 Ok(t) => Some(t),
                 Err(e) => {
                     error!("Could not save image {}", e);
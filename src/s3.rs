@@ -0,0 +1,213 @@
+//! Just enough of an S3 client to store images in a bucket of any S3 compatible service
+//!
+//! Requests are signed with AWS signature version 4 and use path style URLs, so minio and friends work too.
+
+use std::env;
+use std::io::Read;
+use std::time::Duration;
+
+use chrono::Utc;
+use hyper::{self, Client, Url};
+use hyper::client::Response;
+use hyper::header::{Headers, Host};
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use rustc_serialize::hex::ToHex;
+use sha2::{Sha256, Digest};
+
+use error;
+
+struct Config {
+    endpoint: String,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    /// Where the bucket is reachable for browsers, e.g. a CDN, defaults to the endpoint
+    public_url: Option<String>,
+}
+
+lazy_static! {
+    /// Images only go to S3 when `S3_BUCKET` is set, everything else is ignored otherwise
+    static ref CONFIG: Option<Config> = {
+        env::var("S3_BUCKET").ok().map(|bucket| Config {
+            endpoint: env::var("S3_ENDPOINT").unwrap_or_else(|_| "https://s3.amazonaws.com".to_owned())
+                .trim_right_matches('/').to_owned(),
+            bucket: bucket,
+            region: env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_owned()),
+            access_key: env::var("S3_ACCESS_KEY").expect("S3_ACCESS_KEY must be set when S3_BUCKET is"),
+            secret_key: env::var("S3_SECRET_KEY").expect("S3_SECRET_KEY must be set when S3_BUCKET is"),
+            public_url: env::var("S3_PUBLIC_URL").ok().map(|u| u.trim_right_matches('/').to_owned()),
+        })
+    };
+}
+
+static TIMEOUT_SECS : u64 = 30;
+static SIGNED_HEADERS : &'static str = "host;x-amz-content-sha256;x-amz-date";
+
+pub fn enabled() -> bool {
+    CONFIG.is_some()
+}
+
+fn config() -> Result<&'static Config, error::FurryError> {
+    CONFIG.as_ref().ok_or(error::FurryError::S3NotConfigured)
+}
+
+/// The URL browsers can load the object under
+pub fn object_url(key: &str) -> Result<String, error::FurryError> {
+    let config = try!(config());
+    Ok(match config.public_url {
+        Some(ref url) => format!("{}/{}", url, key),
+        None => format!("{}/{}/{}", config.endpoint, config.bucket, key),
+    })
+}
+
+pub fn put(key: &str, bytes: &[u8], content_type: &str) -> Result<(), error::FurryError> {
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![content_type.as_bytes().to_vec()]);
+    let res = try!(request(Method::Put, key, bytes, headers));
+    check_status("upload", key, &res, &[StatusCode::Ok])
+}
+
+/// The object as a stream, it is not buffered in memory
+pub fn open(key: &str) -> Result<Response, error::FurryError> {
+    let res = try!(request(Method::Get, key, &[], Headers::new()));
+    try!(check_status("download", key, &res, &[StatusCode::Ok]));
+    Ok(res)
+}
+
+pub fn get(key: &str) -> Result<Vec<u8>, error::FurryError> {
+    let mut bytes = Vec::new();
+    try!(try!(open(key)).read_to_end(&mut bytes));
+    Ok(bytes)
+}
+
+/// Removes the object, one that is already gone is fine as well
+pub fn delete(key: &str) -> Result<(), error::FurryError> {
+    let res = try!(request(Method::Delete, key, &[], Headers::new()));
+    check_status("delete", key, &res, &[StatusCode::NoContent, StatusCode::Ok, StatusCode::NotFound])
+}
+
+fn check_status(op: &'static str, key: &str, res: &Response, ok: &[StatusCode]) -> Result<(), error::FurryError> {
+    if ok.contains(&res.status) {
+        Ok(())
+    } else {
+        Err(error::FurryError::S3Status { op: op, key: key.to_owned(), status: res.status })
+    }
+}
+
+fn request(method: Method, key: &str, body: &[u8], mut headers: Headers) -> Result<Response, error::FurryError> {
+    let config = try!(config());
+    let url = try!(Url::parse(&format!("{}/{}/{}", config.endpoint, config.bucket, key))
+                   .map_err(hyper::Error::Uri));
+    let hostname = url.host_str().unwrap_or("").to_owned();
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", hostname, port),
+        None => hostname.clone(),
+    };
+
+    let now = Utc::now();
+    let date = now.format("%Y%m%d").to_string();
+    let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let payload_hash = sha256(body).to_hex();
+
+    let canonical = canonical_request(&method.to_string(), url.path(), url.query().unwrap_or(""), &host,
+                                      &payload_hash, &timestamp);
+    let scope = format!("{}/{}/s3/aws4_request", date, config.region);
+    let key = signing_key(&config.secret_key, &date, &config.region, "s3");
+    let signature = sign(&key, &timestamp, &scope, &canonical);
+
+    headers.set(Host { hostname: hostname, port: url.port() });
+    headers.set_raw("x-amz-date", vec![timestamp.into_bytes()]);
+    headers.set_raw("x-amz-content-sha256", vec![payload_hash.into_bytes()]);
+    headers.set_raw("Authorization", vec![format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                                                  config.access_key, scope, SIGNED_HEADERS, signature).into_bytes()]);
+
+    let mut client = Client::new();
+    client.set_read_timeout(Some(Duration::from_secs(TIMEOUT_SECS)));
+    client.set_write_timeout(Some(Duration::from_secs(TIMEOUT_SECS)));
+    Ok(try!(client.request(method, url).headers(headers).body(body).send()))
+}
+
+/// The canonical form of a request that only signs `SIGNED_HEADERS`, `query` has to be canonical already
+fn canonical_request(method: &str, path: &str, query: &str, host: &str, payload_hash: &str, timestamp: &str)
+    -> String
+{
+    format!("{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, query, host, payload_hash, timestamp, SIGNED_HEADERS, payload_hash)
+}
+
+fn signing_key(secret_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let mut key = hmac(format!("AWS4{}", secret_key).as_bytes(), date.as_bytes());
+    for part in &[region, service, "aws4_request"] {
+        key = hmac(&key, part.as_bytes());
+    }
+    key
+}
+
+fn sign(key: &[u8], timestamp: &str, scope: &str, canonical: &str) -> String {
+    let to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", timestamp, scope, sha256(canonical.as_bytes()).to_hex());
+    hmac(key, to_sign.as_bytes()).to_hex()
+}
+
+fn sha256(data: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::default();
+    hasher.input(data);
+    hasher.result().to_vec()
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut key = if key.len() > 64 { sha256(key) } else { key.to_vec() };
+    key.resize(64, 0);
+
+    let mut inner = Sha256::default();
+    inner.input(&key.iter().map(|b| b ^ 0x36).collect::<Vec<u8>>());
+    inner.input(data);
+
+    let mut outer = Sha256::default();
+    outer.input(&key.iter().map(|b| b ^ 0x5c).collect::<Vec<u8>>());
+    outer.input(&inner.result());
+    outer.result().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The expected values come from RFC 4231 and the AWS signature version 4 documentation
+    #[test]
+    fn hmac_matches_rfc_4231() {
+        assert_eq!(hmac(b"Jefe", b"what do ya want for nothing?").to_hex(),
+                   "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+    }
+
+    #[test]
+    fn signing_key_matches_the_aws_example() {
+        let key = signing_key("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", "20120215", "us-east-1", "iam");
+        assert_eq!(key.to_hex(), "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d");
+    }
+
+    #[test]
+    fn signature_matches_the_aws_s3_example() {
+        let payload_hash = sha256(&[]).to_hex();
+        let canonical = canonical_request("GET", "/", "lifecycle=", "examplebucket.s3.amazonaws.com",
+                                          &payload_hash, "20130524T000000Z");
+        let key = signing_key("wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY", "20130524", "us-east-1", "s3");
+        assert_eq!(sign(&key, "20130524T000000Z", "20130524/us-east-1/s3/aws4_request", &canonical),
+                   "fea454ca298b7da1c68078a5d1bdbfbbe0d65c699e0f91ac7a200a0136783543");
+    }
+
+    /// Needs a bucket configured through the `S3_*` variables, e.g. a local minio
+    #[cfg(feature = "s3-tests")]
+    #[test]
+    fn objects_round_trip_through_the_bucket() {
+        let key = "furry-cafe-s3-test/round-trip.txt";
+        put(key, b"some bytes", "text/plain").unwrap();
+        assert_eq!(get(key).unwrap(), b"some bytes");
+
+        delete(key).unwrap();
+        assert!(get(key).is_err());
+        // Deleting is idempotent
+        delete(key).unwrap();
+    }
+}
@@ -41,8 +41,8 @@ impl<'a> Render for Image<'a> {
 
         match self.size {
             Some((width, height)) => {
-                match self.img.get_with_size(width, height) {
-                    Ok(i) => img(&i.get_path()[..]),
+                match self.img.get_with_size(width, height).and_then(|i| i.get_path()) {
+                    Ok(p) => img(&p[..]),
                     Err(e) => {
                         error!("Could not get image resized: {:?}", e);
                         img("/not_found.png")
@@ -50,7 +50,13 @@ impl<'a> Render for Image<'a> {
                 }
             }
             None => {
-                img(&self.img.get_path())
+                match self.img.get_path() {
+                    Ok(p) => img(&p[..]),
+                    Err(e) => {
+                        error!("Could not get image path: {:?}", e);
+                        img("/not_found.png")
+                    }
+                }
             }
         }
     }
@@ -103,7 +103,7 @@ pub fn show(sub: &Submission, data: &LayoutData, req: &mut Request) -> Result<Ma
             div.row (Column::new(html! {
                 div.submission.clearfix {
                     @if let Some(ref image) = image {
-                        img src=(image.get_path()) alt=(format!("{}'s Submission", user.name)) /
+                        img src=(try!(image.get_path())) alt=(format!("{}'s Submission", user.name)) /
                     } @else {
                         img src="/assets/images/missing.png" alt=(format!("{}'s Submission", user.name)) /
                     }
@@ -123,7 +123,7 @@ pub fn show(sub: &Submission, data: &LayoutData, req: &mut Request) -> Result<Ma
                     div.sub_actions {
                         a.btn.btn-primary href=(url!(format!("/users/{}/edit", user.id))) "Favorit"
                         @if let Some(ref image) = image {
-                            a.btn.btn-secondary href=(image.get_path()) "Full Size"
+                            a.btn.btn-secondary href=(try!(image.get_path())) "Full Size"
                         }
                         @if req.current_user_can(authorization::SameUserAuthAs(&user)) {
                             a.btn.btn-info href=(url!(format!("/submissions/{}/edit", sub.id))) "Edit"
@@ -66,7 +66,7 @@ pub fn show(user: &User, role: Role, profile: &UserProfile, data: &LayoutData, r
             @if let Some(image) = banner {
                 div.row {
                     (Column::new(html! {
-                        div.banner style=(format!("background-image: url('{}');height: {};", image.get_path(), image.height)) ""
+                        div.banner style=(format!("background-image: url('{}');height: {};", try!(image.get_path()), image.height)) ""
                     }))
                 }
             }